@@ -6,7 +6,8 @@ CREATE TABLE user (
 	id BIGINT UNSIGNED NOT NULL UNIQUE PRIMARY KEY AUTO_INCREMENT,
 	username VARCHAR(80) NOT NULL UNIQUE,
 	password CHAR(73) NOT NULL,
-	is_superuser SET('Y', 'N') NOT NULL DEFAULT 'N'
+	is_superuser SET('Y', 'N') NOT NULL DEFAULT 'N',
+	quota BIGINT UNSIGNED NULL DEFAULT NULL
 );
 
 CREATE TABLE audit (
@@ -14,7 +15,7 @@ CREATE TABLE audit (
 	user BIGINT UNSIGNED NULL DEFAULT NULL,
 	time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
 	address INT(32) UNSIGNED NOT NULL,
-	event SET('AUTH', 'NEW_STASH', 'DELETE_STASH', 'LIST', 'DOWNLOAD', 'UPLOAD', 'DELETE_FILE') NOT NULL,
+	event SET('AUTH', 'NEW_STASH', 'DELETE_STASH', 'RENAME_STASH', 'LIST', 'DOWNLOAD', 'UPLOAD', 'DELETE_FILE', 'MOVE_FILE', 'COPY_FILE', 'NEW_SNAPSHOT', 'DELETE_SNAPSHOT') NOT NULL,
 	success SET('Y', 'N') NOT NULL,
 	info TEXT NULL DEFAULT NULL,
 
@@ -27,6 +28,7 @@ CREATE TABLE stash (
 	id BIGINT UNSIGNED NOT NULL UNIQUE PRIMARY KEY AUTO_INCREMENT,
 	owner BIGINT UNSIGNED NOT NULL,
 	name VARCHAR(80) NOT NULL,
+	quota BIGINT UNSIGNED NULL DEFAULT NULL,
 
 	UNIQUE (owner, name),
 	CONSTRAINT FOREIGN KEY (owner) REFERENCES user(id)
@@ -34,14 +36,76 @@ CREATE TABLE stash (
 		ON UPDATE CASCADE
 );
 
+CREATE TABLE content (
+	hash CHAR(64) NOT NULL UNIQUE PRIMARY KEY,
+	size BIGINT UNSIGNED NOT NULL,
+	refs BIGINT UNSIGNED NOT NULL DEFAULT 0
+);
+
 CREATE TABLE file (
 	id BIGINT UNSIGNED NOT NULL UNIQUE PRIMARY KEY AUTO_INCREMENT,
 	stash BIGINT UNSIGNED NOT NULL,
 	name VARCHAR(256) NOT NULL,
+	revision BIGINT UNSIGNED NOT NULL DEFAULT 1,
 	update_time BIGINT UNSIGNED NOT NULL,
+	size BIGINT UNSIGNED NOT NULL DEFAULT 0,
+	hash CHAR(64) NULL DEFAULT NULL,
 
 	UNIQUE (stash, name),
+	CONSTRAINT FOREIGN KEY (stash) REFERENCES stash(id)
+		ON DELETE CASCADE
+		ON UPDATE CASCADE,
+	CONSTRAINT FOREIGN KEY (hash) REFERENCES content(hash)
+);
+
+CREATE TABLE revision (
+	file BIGINT UNSIGNED NOT NULL,
+	revision BIGINT UNSIGNED NOT NULL,
+	update_time BIGINT UNSIGNED NOT NULL,
+	size BIGINT UNSIGNED NOT NULL,
+	hash CHAR(64) NULL DEFAULT NULL,
+
+	PRIMARY KEY (file, revision),
+	CONSTRAINT FOREIGN KEY (file) REFERENCES file(id)
+		ON DELETE CASCADE
+		ON UPDATE CASCADE,
+	CONSTRAINT FOREIGN KEY (hash) REFERENCES content(hash)
+);
+
+CREATE TABLE upload (
+	token CHAR(32) NOT NULL UNIQUE PRIMARY KEY,
+	stash BIGINT UNSIGNED NOT NULL,
+	name VARCHAR(256) NOT NULL,
+	size BIGINT UNSIGNED NOT NULL,
+	update_time BIGINT UNSIGNED NOT NULL,
+
 	CONSTRAINT FOREIGN KEY (stash) REFERENCES stash(id)
 		ON DELETE CASCADE
 		ON UPDATE CASCADE
 );
+
+CREATE TABLE snapshot (
+	id BIGINT UNSIGNED NOT NULL UNIQUE PRIMARY KEY AUTO_INCREMENT,
+	stash BIGINT UNSIGNED NOT NULL,
+	name VARCHAR(80) NOT NULL,
+	time BIGINT UNSIGNED NOT NULL,
+
+	UNIQUE (stash, name),
+	CONSTRAINT FOREIGN KEY (stash) REFERENCES stash(id)
+		ON DELETE CASCADE
+		ON UPDATE CASCADE
+);
+
+CREATE TABLE snapshot_file (
+	snapshot BIGINT UNSIGNED NOT NULL,
+	name VARCHAR(256) NOT NULL,
+	update_time BIGINT UNSIGNED NOT NULL,
+	size BIGINT UNSIGNED NOT NULL,
+	hash CHAR(64) NOT NULL,
+
+	PRIMARY KEY (snapshot, name),
+	CONSTRAINT FOREIGN KEY (snapshot) REFERENCES snapshot(id)
+		ON DELETE CASCADE
+		ON UPDATE CASCADE,
+	CONSTRAINT FOREIGN KEY (hash) REFERENCES content(hash)
+);
@@ -3,7 +3,9 @@ use anyhow::Result;
 
 pub struct Args {
 	pub exec: String,
-	pub config: Option<String>
+	pub config: Option<String>,
+	/// Re-encrypt stored data with the current key instead of running the server
+	pub rekey: bool
 }
 
 impl Args {
@@ -13,13 +15,15 @@ impl Args {
 		let mut args = std::env::args();
 		let res = Args {
 			exec: args.next().unwrap(),
-			config: None
+			config: None,
+			rekey: false
 		};
 
 		let  (res, next) = args.try_fold((res, Flag), |(args, next), arg| {
 			match next {
 				Flag => match arg.as_str() {
 					"-c" | "--cfg" => Ok((args, Config)),
+					"--rekey" => Ok((Args { rekey: true, ..args }, Flag)),
 					_ => Err(Error::UnknownFlag(arg))
 				},
 				Config => Ok((Args { config: Some(arg), ..args }, Flag))
@@ -27,6 +27,14 @@ pub struct Config {
 	pub db_password: String,
 	pub db_ssl: bool,
 	pub storage_path: String,
+	pub upload_expiry: u64,
+	pub keep_revisions: u64,
+	/// zstd level for stored data, 0 disables compression
+	pub compression_level: i32,
+	/// Enables encryption of stored data
+	pub storage_key_file: Option<String>,
+	/// Longest manifest accepted by `sync`
+	pub max_manifest_size: u64,
 }
 
 impl Default for Config {
@@ -45,7 +53,12 @@ impl Default for Config {
 			db_user: "".into(),
 			db_password: "".into(),
 			db_ssl: false,
-			storage_path: "/var/autobak".into()
+			storage_path: "/var/autobak".into(),
+			upload_expiry: 86400,
+			keep_revisions: 5,
+			compression_level: 0,
+			storage_key_file: None,
+			max_manifest_size: 64 * 1024 * 1024
 		}
 	}
 }
@@ -69,6 +82,11 @@ impl Config {
 				"dbpassword" => Ok(Config { db_password: val.clone(), ..cfg }),
 				"dbssl" => Ok(Config { db_ssl: val.parse()?, ..cfg }),
 				"storagepath" => Ok(Config { storage_path: val.clone(), ..cfg }),
+				"uploadexpiry" => Ok(Config { upload_expiry: val.parse()?, ..cfg }),
+				"keeprevisions" => Ok(Config { keep_revisions: val.parse()?, ..cfg }),
+				"compressionlevel" => Ok(Config { compression_level: val.parse()?, ..cfg }),
+				"storagekeyfile" => Ok(Config { storage_key_file: Some(val.clone()), ..cfg }),
+				"maxmanifestsize" => Ok(Config { max_manifest_size: val.parse()?, ..cfg }),
 				_ => Err(anyhow::Error::from(Error::UnknownOption(opt.clone())))
 			}
 		})?;
@@ -9,11 +9,13 @@ use async_std::{
 };
 use openssl::ssl::Ssl;
 use crate::{debug, error};
-use state::Expectation;
+use state::{Expectation, Response, ResponseContent};
 
 pub mod acceptor;
 mod stream;
 mod state;
+mod mux;
+mod binary;
 
 pub async fn handle_client(info: Arc<crate::ServerInfo>, client: TcpStream) {
 	debug!("Handling connection from {}", client.peer_addr().unwrap());
@@ -29,47 +31,87 @@ pub async fn handle_client(info: Arc<crate::ServerInfo>, client: TcpStream) {
 		let mut stream = stream::Stream::new(&info.ssl, client).await?;
 		let mut state = state::State::new(info.clone(), addr);
 
+		// Requests are handled strictly one after another in the order they arrive,
+		// no matter how they are split between reads, and every request gets
+		// exactly one response (except empty lines). Data of an upload follows its
+		// request directly and anything after it is the next request. Once the
+		// session ends, whatever else was received is ignored.
 		let mut buffer = [0; 4096];
 		let mut buf_len = 0;
+		// the binary protocol can only be chosen with the first bytes
+		let mut negotiated = false;
+		// set after a line that doesn't fit into the buffer, the rest of it is dropped
+		let mut skip_line = false;
 
 		while !state.end() {
 			let len = stream.read(&mut buffer[buf_len..]).await?;
 			if len == 0 {
 				break;
 			}
+			buf_len += len;
 
-			match state.expects() {
-				Expectation::Line => {
-					let mut nl_pos = None;
-					for check_pos in buf_len..buf_len + len {
-						if buffer[check_pos] == b'\n' {
-							let start = match nl_pos {
-								Some(nl_pos) => nl_pos + 1,
-								None => 0
-							};
-							let res = state.next_piece(&buffer[start..check_pos]).await;
-							match res {
-								Ok(res) => stream.write_all(&(&res).into() as &Vec<_>).await?,
-								Err(err) => {
-									stream.write_all(b"err:server\n").await?;
-									return Err(err);
-								}
-							}
-							nl_pos = Some(check_pos);
-						}
-					}
+			if !negotiated {
+				if buf_len < binary::PREAMBLE.len() && binary::PREAMBLE.starts_with(&buffer[..buf_len]) {
+					continue;
+				}
+				negotiated = true;
+				if buffer[..buf_len].starts_with(binary::PREAMBLE) {
+					binary::run(&mut stream, &mut state, &buffer[binary::PREAMBLE.len()..buf_len]).await?;
+					break;
+				}
+			}
 
-					match nl_pos {
+			let mut start = 0;
+			if skip_line {
+				match buffer[..buf_len].iter().position(|ch| *ch == b'\n') {
+					Some(nl_pos) => {
+						start = nl_pos + 1;
+						skip_line = false;
+					}
+					None => {
+						buf_len = 0;
+						continue;
+					}
+				}
+			}
+			while start < buf_len && !state.end() {
+				let res = match state.expects() {
+					Expectation::Line => match buffer[start..buf_len].iter().position(|ch| *ch == b'\n') {
 						Some(nl_pos) => {
-							for pos in nl_pos + 1..buf_len + len {
-								buffer[pos - nl_pos - 1] = buffer[pos];
-							}
-							buf_len = buf_len + len - nl_pos - 1;
+							let res = state.next_piece(&buffer[start..start + nl_pos]).await;
+							start += nl_pos + 1;
+							res
 						}
-						None => buf_len += len
+						None => break
 					}
-				}
-				Expectation::Nothing => break
+					Expectation::Binary(left) => {
+						let end = if left < (buf_len - start) as u64 {
+							start + left as usize
+						} else {
+							buf_len
+						};
+						let res = state.next_data(&buffer[start..end]).await;
+						start = end;
+						res
+					}
+					Expectation::Frames | Expectation::Nothing => break
+				};
+				send_response(&mut stream, &state, res).await?;
+			}
+
+			buffer.copy_within(start..buf_len, 0);
+			buf_len -= start;
+
+			if buf_len == buffer.len() && matches!(state.expects(), Expectation::Line) {
+				// the line has no tag yet
+				stream.write_all(&Response::BadFormat.header(state.version())).await?;
+				skip_line = true;
+				buf_len = 0;
+			}
+
+			if let Expectation::Frames = state.expects() {
+				mux::run(&mut stream, &state, &buffer[..buf_len]).await?;
+				break;
 			}
 		}
 
@@ -82,6 +124,36 @@ pub async fn handle_client(info: Arc<crate::ServerInfo>, client: TcpStream) {
 	}
 }
 
+const CHUNK_SIZE: usize = 65536;
+
+async fn send_response(stream: &mut stream::Stream, state: &state::State, res: Response) -> anyhow::Result<()> {
+	stream.write_all(&header(state, &res)).await?;
+	if let Response::Ok(ResponseContent::Binary(mut reader)) = res {
+		let mut chunk = vec![0; CHUNK_SIZE];
+		loop {
+			let len = reader.read(&mut chunk).await?;
+			if len == 0 {
+				break;
+			}
+			stream.write_all(&chunk[..len]).await?;
+		}
+	}
+	Ok(())
+}
+
+/// Response header for the protocol version of the client with the request tag in front of it
+fn header(state: &state::State, res: &Response) -> Vec<u8> {
+	tagged(res.header(state.version()), state.tag())
+}
+
+/// Puts the request tag in front of a response header
+fn tagged(mut header: Vec<u8>, tag: Option<&str>) -> Vec<u8> {
+	if let (Some(tag), false) = (tag, header.is_empty()) {
+		header.splice(0..0, format!("~{tag} ").into_bytes());
+	}
+	header
+}
+
 #[derive(Debug)]
 pub struct UnsupportenAddr;
 
@@ -92,3 +164,18 @@ impl Display for UnsupportenAddr {
 }
 
 impl std::error::Error for UnsupportenAddr {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tag_comes_first() {
+		let lines = Response::Ok(ResponseContent::Lines(vec!["a".into(), "b".into()]));
+		assert_eq!(tagged(lines.header(3), Some("t1")), b"~t1 ok:l2\na\nb\n");
+		assert_eq!(tagged(Response::BadArgs.header(2), Some("t1")), b"~t1 err:badargs\n");
+		assert_eq!(tagged(Response::Ok(ResponseContent::Empty).header(3), None), b"ok:0\n");
+		// nothing is sent for requests without a response
+		assert!(tagged(Response::None.header(3), Some("t1")).is_empty());
+	}
+}
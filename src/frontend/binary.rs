@@ -0,0 +1,234 @@
+//! Length-prefixed binary protocol, chosen by sending `PREAMBLE` before anything else.
+//! The server confirms it by sending the same preamble back.
+//!
+//! Every message is `<length: u32> <type: u8> <payload>`, numbers are big endian and
+//! the length covers the payload only. Strings are `<length: u32> <UTF-8 bytes>`.
+//!
+//! Client messages:
+//! - `REQUEST`: `<tag: u32> <count: u16> <string>*`, the command and its arguments,
+//!   starting with the user name and the password before logging in
+//! - `DATA`: data of an upload, right after its request
+//!
+//! Server messages, each of them carries the tag of the request it answers:
+//! - `OK`: `<tag: u32>`
+//! - `LINES`: `<tag: u32> <count: u32> <string>*`, lines are formatted as in the line protocol
+//!   with names always quoted when needed
+//! - `BINARY`: `<tag: u32> <size: u64>`, followed by `DATA` messages with the content
+//! - `ERROR`: `<tag: u32> <code: string> <correlation id: string> <message: string>`,
+//!   as they follow `err:` in the line protocol
+use std::fmt::{self, Display};
+use anyhow::Result;
+use async_std::io::{ReadExt, WriteExt};
+use crate::debug;
+use super::{
+	CHUNK_SIZE,
+	state::{State, Expectation, Response, ResponseContent, args::Args},
+	stream::Stream
+};
+
+pub const PREAMBLE: &[u8] = b"\0ABK\x02";
+
+const REQUEST: u8 = 1;
+const DATA: u8 = 2;
+const OK: u8 = 16;
+const LINES: u8 = 17;
+const BINARY: u8 = 18;
+const ERROR: u8 = 19;
+
+const MESSAGE_HEADER_LEN: usize = 5;
+/// Limit for messages from the client
+const MAX_PAYLOAD: usize = CHUNK_SIZE + 1024;
+
+pub async fn run(stream: &mut Stream, state: &mut State, received: &[u8]) -> Result<()> {
+	debug!("Switching to binary protocol");
+	state.use_binary();
+	stream.write_all(PREAMBLE).await?;
+	let mut input = Vec::from(received);
+	let mut buffer = vec![0; CHUNK_SIZE];
+	// tag of the request whose data is being received
+	let mut tag = 0;
+	while !state.end() {
+		let mut start = 0;
+		while let Some((kind, payload)) = next_message(&input[start..])? {
+			start += MESSAGE_HEADER_LEN + payload.len();
+			let res = match kind {
+				REQUEST => match parse_request(payload) {
+					Some((request_tag, args)) => {
+						tag = request_tag;
+						state.next_request(args).await
+					}
+					None => return Err(BadMessage("malformed request").into())
+				}
+				DATA => match state.expects() {
+					Expectation::Binary(left) if payload.len() as u64 <= left => state.next_data(payload).await,
+					_ => return Err(BadMessage("unexpected data").into())
+				}
+				_ => return Err(BadMessage("unknown message type").into())
+			};
+			send_response(stream, tag, res).await?;
+			if state.end() {
+				break;
+			}
+		}
+		input.drain(..start);
+
+		if !state.end() {
+			let len = stream.read(&mut buffer).await?;
+			if len == 0 {
+				break;
+			}
+			input.extend_from_slice(&buffer[..len]);
+		}
+	}
+	Ok(())
+}
+
+/// Splits a complete message off the start of received data
+fn next_message(data: &[u8]) -> Result<Option<(u8, &[u8])>> {
+	if data.len() < MESSAGE_HEADER_LEN {
+		return Ok(None);
+	}
+	let len = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+	if len > MAX_PAYLOAD {
+		return Err(BadMessage("message is too long").into());
+	}
+	Ok(data.get(MESSAGE_HEADER_LEN..MESSAGE_HEADER_LEN + len).map(|payload| (data[4], payload)))
+}
+
+/// Returns `None` if the lengths don't add up or a string isn't valid UTF-8
+fn parse_request(payload: &[u8]) -> Option<(u32, Args)> {
+	let tag = u32::from_be_bytes(payload.get(0..4)?.try_into().ok()?);
+	let count = u16::from_be_bytes(payload.get(4..6)?.try_into().ok()?);
+	let mut pos = 6;
+	let mut list = Vec::with_capacity(count as usize);
+	for _ in 0..count {
+		let len = u32::from_be_bytes(payload.get(pos..pos + 4)?.try_into().ok()?) as usize;
+		pos += 4;
+		list.push(String::from_utf8(payload.get(pos..pos + len)?.into()).ok()?);
+		pos += len;
+	}
+	if pos != payload.len() {
+		return None;
+	}
+	Some((tag, Args::from_list(list)))
+}
+
+async fn send_response(stream: &mut Stream, tag: u32, res: Response) -> Result<()> {
+	let mut payload = Vec::from(tag.to_be_bytes());
+	let kind = match res {
+		Response::None => return Ok(()),
+		Response::Ok(ResponseContent::Empty) => OK,
+		Response::Ok(ResponseContent::Lines(ref lines)) => {
+			payload.extend_from_slice(&(lines.len() as u32).to_be_bytes());
+			for line in lines {
+				put_string(&mut payload, line);
+			}
+			LINES
+		}
+		Response::Ok(ResponseContent::Binary(ref reader)) => {
+			payload.extend_from_slice(&reader.len().to_be_bytes());
+			BINARY
+		}
+		ref res => {
+			put_string(&mut payload, res.error_code().unwrap_or("server"));
+			put_string(&mut payload, res.correlation_id().unwrap_or("-"));
+			put_string(&mut payload, res.message());
+			ERROR
+		}
+	};
+	send(stream, kind, &payload).await?;
+	if let Response::Ok(ResponseContent::Binary(mut reader)) = res {
+		let mut chunk = vec![0; CHUNK_SIZE];
+		loop {
+			let len = reader.read(&mut chunk).await?;
+			if len == 0 {
+				break;
+			}
+			send(stream, DATA, &chunk[..len]).await?;
+		}
+	}
+	Ok(())
+}
+
+fn put_string(buffer: &mut Vec<u8>, text: &str) {
+	buffer.extend_from_slice(&(text.len() as u32).to_be_bytes());
+	buffer.extend_from_slice(text.as_bytes());
+}
+
+async fn send(stream: &mut Stream, kind: u8, payload: &[u8]) -> Result<()> {
+	let mut message = Vec::with_capacity(MESSAGE_HEADER_LEN + payload.len());
+	message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+	message.push(kind);
+	message.extend_from_slice(payload);
+	stream.write_all(&message).await?;
+	Ok(())
+}
+
+#[derive(Debug)]
+pub struct BadMessage(&'static str);
+
+impl Display for BadMessage {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Invalid message in binary protocol: {}", self.0)
+	}
+}
+
+impl std::error::Error for BadMessage {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn request(tag: u32, strings: &[&[u8]]) -> Vec<u8> {
+		let mut res = Vec::from(tag.to_be_bytes());
+		res.extend_from_slice(&(strings.len() as u16).to_be_bytes());
+		for string in strings {
+			res.extend_from_slice(&(string.len() as u32).to_be_bytes());
+			res.extend_from_slice(string);
+		}
+		res
+	}
+
+	#[test]
+	fn request_is_parsed() {
+		let (tag, args) = parse_request(&request(7, &[b"list", b"files", b"my stash", b""])).unwrap();
+		assert_eq!(tag, 7);
+		assert_eq!(args.len(), 4);
+		assert_eq!(args.get(2), Some("my stash"));
+		assert_eq!(args.get(3), Some(""));
+		let (_, args) = parse_request(&request(0, &[])).unwrap();
+		assert_eq!(args.len(), 0);
+	}
+
+	#[test]
+	fn broken_requests_are_rejected() {
+		let good = request(1, &[b"stat", b"stash", b"file"]);
+		// cut anywhere, including inside the tag, the count and a length
+		for len in 0..good.len() {
+			assert!(parse_request(&good[..len]).is_none(), "cut at {len}");
+		}
+		let mut trailing = good.clone();
+		trailing.push(0);
+		assert!(parse_request(&trailing).is_none());
+
+		let mut overlong = request(1, &[b"stat"]);
+		overlong[6..10].copy_from_slice(&u32::MAX.to_be_bytes());
+		assert!(parse_request(&overlong).is_none());
+
+		let mut miscounted = request(1, &[b"stat"]);
+		miscounted[4..6].copy_from_slice(&2u16.to_be_bytes());
+		assert!(parse_request(&miscounted).is_none());
+
+		assert!(parse_request(&request(1, &[b"\xff\xfe"])).is_none());
+	}
+
+	#[test]
+	fn messages_are_split() {
+		let mut data = vec![0, 0, 0, 3, DATA, 1, 2, 3, 0, 0];
+		assert_eq!(next_message(&data).unwrap(), Some((DATA, &[1, 2, 3][..])));
+		assert_eq!(next_message(&data[8..]).unwrap(), None);
+		assert_eq!(next_message(&data[..7]).unwrap(), None);
+		data[0..4].copy_from_slice(&(MAX_PAYLOAD as u32 + 1).to_be_bytes());
+		assert!(next_message(&data).is_err());
+	}
+}
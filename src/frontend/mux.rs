@@ -0,0 +1,287 @@
+//! Multiplexed mode, entered with the `mux` command.
+//!
+//! Everything after the response to `mux` is sent in frames:
+//! `<channel: u32> <type: u8> <length: u32> <payload>`, numbers are big endian
+//! and payloads are at most 64 KiB long. Channels are picked by the client,
+//! every channel handles one request at a time just like a connection in line mode.
+//!
+//! - `REQUEST` (client) carries a request line without the line break
+//! - `RESPONSE` (server) carries a response header as in line mode, with the lines of a list.
+//!   A response that doesn't fit into one frame continues in the next `RESPONSE` frames
+//!   of the channel, they are sent one after another and are read just like lines in line mode.
+//! - `DATA` carries data of an upload after its request, or of a download after its response
+//! - `CREDIT` grants the other side as many more bytes of `DATA` on the channel as its
+//!   payload (`u32`) says. Both sides start with `WINDOW` bytes for every request.
+//!   The server handles uploaded data before it reads the next frame and grants credit
+//!   for it right away, so clients only have to wait for credit if they like to.
+//! - `CANCEL` (client) drops whatever the channel is doing, the server confirms it with `CANCEL`.
+//!   The server also sends it on its own if a download fails halfway.
+//!
+//! A broken frame ends the connection.
+use std::{
+	collections::{HashMap, VecDeque},
+	fmt::{self, Display},
+	pin::Pin,
+	task::Poll
+};
+use anyhow::Result;
+use async_std::io::{Read as AsyncRead, ReadExt, WriteExt};
+use crate::{debug, error, info::file::Reader};
+use super::{
+	CHUNK_SIZE,
+	header,
+	state::{State, Expectation, Response, ResponseContent},
+	stream::Stream
+};
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const DATA: u8 = 2;
+const CREDIT: u8 = 3;
+const CANCEL: u8 = 4;
+
+const FRAME_HEADER_LEN: usize = 9;
+/// Credit both sides start with for every request
+const WINDOW: u64 = 256 * 1024;
+/// Channels with a request in progress
+const MAX_CHANNELS: usize = 64;
+
+pub async fn run(stream: &mut Stream, state: &State, received: &[u8]) -> Result<()> {
+	debug!("Switching to multiplexed mode");
+	let mut mux = Mux {
+		stream,
+		state,
+		channels: HashMap::new(),
+		downloads: VecDeque::new()
+	};
+	let mut input = Vec::from(received);
+	let mut buffer = vec![0; CHUNK_SIZE];
+	loop {
+		let mut start = 0;
+		while let Some((channel, kind, payload)) = next_frame(&input[start..])? {
+			start += FRAME_HEADER_LEN + payload.len();
+			mux.handle(channel, kind, payload).await?;
+		}
+		input.drain(..start);
+
+		// downloads are sent while there is credit, reading only when the client has something to say
+		let len = if mux.send_data().await? {
+			try_read(mux.stream, &mut buffer).await?
+		} else {
+			Some(mux.stream.read(&mut buffer).await?)
+		};
+		match len {
+			Some(0) => break,
+			Some(len) => input.extend_from_slice(&buffer[..len]),
+			None => ()
+		}
+	}
+	Ok(())
+}
+
+struct Mux<'a> {
+	stream: &'a mut Stream,
+	state: &'a State,
+	channels: HashMap<u32, Channel>,
+	/// Channels with a download in progress, in the order they get to send data
+	downloads: VecDeque<u32>
+}
+
+struct Channel {
+	state: State,
+	download: Option<Reader>,
+	/// Bytes of download data the client is ready to receive
+	credit: u64
+}
+
+impl<'a> Mux<'a> {
+	async fn handle(&mut self, id: u32, kind: u8, payload: &[u8]) -> Result<()> {
+		match kind {
+			REQUEST => {
+				if !self.channels.contains_key(&id) && self.channels.len() >= MAX_CHANNELS {
+					return self.send(id, RESPONSE, &Response::Busy.header(self.state.version())).await;
+				}
+				let channel = self.channels.entry(id).or_insert_with(|| Channel {
+					state: self.state.channel(),
+					download: None,
+					credit: 0
+				});
+				if channel.download.is_some() || !matches!(channel.state.expects(), Expectation::Line) {
+					let header = Response::Busy.header(channel.state.version());
+					return self.send(id, RESPONSE, &header).await;
+				}
+				channel.credit = WINDOW;
+				let res = channel.state.next_piece(payload).await;
+				self.respond(id, res).await
+			}
+			DATA => {
+				let channel = match self.channels.get_mut(&id) {
+					Some(channel) => channel,
+					None => return Err(BadFrame("data on an idle channel").into())
+				};
+				match channel.state.expects() {
+					Expectation::Binary(left) if payload.len() as u64 <= left => (),
+					_ => return Err(BadFrame("unexpected data").into())
+				}
+				let res = channel.state.next_data(payload).await;
+				if !payload.is_empty() && self.channels.contains_key(&id) {
+					self.send(id, CREDIT, &(payload.len() as u32).to_be_bytes()).await?;
+				}
+				self.respond(id, res).await
+			}
+			CREDIT => match payload.try_into() {
+				Ok(credit) => {
+					if let Some(channel) = self.channels.get_mut(&id) {
+						channel.credit = channel.credit.saturating_add(u32::from_be_bytes(credit) as u64);
+					}
+					Ok(())
+				}
+				Err(_) => Err(BadFrame("credit of a wrong size").into())
+			}
+			CANCEL => {
+				// dropping the state removes an unfinished upload
+				self.channels.remove(&id);
+				self.downloads.retain(|channel| *channel != id);
+				self.send(id, CANCEL, &[]).await
+			}
+			_ => Err(BadFrame("unknown frame type").into())
+		}
+	}
+
+	/// Sends a response of a channel, binary content is queued to be sent as credit allows
+	async fn respond(&mut self, id: u32, res: Response) -> Result<()> {
+		let channel = match self.channels.get_mut(&id) {
+			Some(channel) => channel,
+			None => return Ok(())
+		};
+		let header = header(&channel.state, &res);
+		if let Response::Ok(ResponseContent::Binary(reader)) = res {
+			if reader.left() > 0 {
+				channel.download = Some(reader);
+				self.downloads.push_back(id);
+			}
+		}
+		// a channel that failed badly is dropped, the client learns about it from the response
+		let idle = channel.download.is_none() && !matches!(channel.state.expects(), Expectation::Binary(_));
+		if idle {
+			self.channels.remove(&id);
+		}
+		for part in header.chunks(CHUNK_SIZE) {
+			self.send(id, RESPONSE, part).await?;
+		}
+		Ok(())
+	}
+
+	/// Sends a piece of the first download that has credit left.
+	/// Returns `false` if nothing could be sent.
+	async fn send_data(&mut self) -> Result<bool> {
+		for _ in 0..self.downloads.len() {
+			let id = match self.downloads.pop_front() {
+				Some(id) => id,
+				None => break
+			};
+			let channel = match self.channels.get_mut(&id) {
+				Some(channel) => channel,
+				None => continue
+			};
+			if channel.credit == 0 {
+				self.downloads.push_back(id);
+				continue;
+			}
+			let reader = channel.download.as_mut().expect("channel is in the download queue");
+			let mut chunk = vec![0; (CHUNK_SIZE as u64).min(channel.credit) as usize];
+			let len = match reader.read(&mut chunk).await {
+				Ok(len) => len,
+				Err(err) => {
+					// only this channel is affected, the client learns about it from `CANCEL`
+					error!("Download on channel {id} failed: {err}");
+					self.channels.remove(&id);
+					self.send(id, CANCEL, &[]).await?;
+					return Ok(true);
+				}
+			};
+			channel.credit -= len as u64;
+			if reader.left() == 0 {
+				self.channels.remove(&id);
+			} else {
+				self.downloads.push_back(id);
+			}
+			if len > 0 {
+				self.send(id, DATA, &chunk[..len]).await?;
+				return Ok(true);
+			}
+		}
+		Ok(false)
+	}
+
+	async fn send(&mut self, id: u32, kind: u8, payload: &[u8]) -> Result<()> {
+		let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+		frame.extend_from_slice(&id.to_be_bytes());
+		frame.push(kind);
+		frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+		frame.extend_from_slice(payload);
+		self.stream.write_all(&frame).await?;
+		Ok(())
+	}
+}
+
+/// Splits a complete frame off the start of received data
+fn next_frame(data: &[u8]) -> Result<Option<(u32, u8, &[u8])>> {
+	if data.len() < FRAME_HEADER_LEN {
+		return Ok(None);
+	}
+	let id = u32::from_be_bytes(data[0..4].try_into().unwrap());
+	let len = u32::from_be_bytes(data[5..9].try_into().unwrap()) as usize;
+	if len > CHUNK_SIZE {
+		return Err(BadFrame("frame is too long").into());
+	}
+	Ok(data.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len).map(|payload| (id, data[4], payload)))
+}
+
+/// Reads whatever has already arrived without waiting for more
+async fn try_read(stream: &mut Stream, buffer: &mut [u8]) -> std::io::Result<Option<usize>> {
+	futures::future::poll_fn(|cx| match Pin::new(&mut *stream).poll_read(cx, buffer) {
+		Poll::Ready(res) => Poll::Ready(res.map(Some)),
+		Poll::Pending => Poll::Ready(Ok(None))
+	}).await
+}
+
+#[derive(Debug)]
+pub struct BadFrame(&'static str);
+
+impl Display for BadFrame {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Invalid frame in multiplexed mode: {}", self.0)
+	}
+}
+
+impl std::error::Error for BadFrame {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn frame(id: u32, kind: u8, payload: &[u8]) -> Vec<u8> {
+		let mut res = Vec::from(id.to_be_bytes());
+		res.push(kind);
+		res.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+		res.extend_from_slice(payload);
+		res
+	}
+
+	#[test]
+	fn frames_are_split() {
+		let mut data = frame(5, REQUEST, b"list");
+		data.extend(frame(6, CREDIT, &[]));
+		assert_eq!(next_frame(&data).unwrap(), Some((5, REQUEST, &b"list"[..])));
+		assert_eq!(next_frame(&data[FRAME_HEADER_LEN + 4..]).unwrap(), Some((6, CREDIT, &[][..])));
+		// incomplete headers and payloads wait for more data
+		for len in 0..FRAME_HEADER_LEN + 4 {
+			assert_eq!(next_frame(&data[..len]).unwrap(), None, "cut at {len}");
+		}
+		assert!(next_frame(&frame(1, DATA, &vec![0; CHUNK_SIZE])).unwrap().is_some());
+		let mut overlong = frame(1, DATA, &[]);
+		overlong[5..9].copy_from_slice(&(CHUNK_SIZE as u32 + 1).to_be_bytes());
+		assert!(next_frame(&overlong).is_err());
+	}
+}
@@ -1,16 +1,45 @@
 use std::net::Ipv4Addr;
 use anyhow::Result;
 use async_std::sync::Arc;
-use crate::info::audit::Event;
+use crate::config::Config;
+use crate::info::{
+	audit::Event,
+	keys,
+	user::RenameResult,
+	file::{File, Reader},
+	stash::{Stash, Change, Transfer},
+	staging::Staging
+};
 #[allow(unused_imports)]
 use crate::{debug, error};
+use upload::{Upload, Finish};
+use manifest::Manifest;
+use args::{Args, quote};
+
+mod upload;
+mod manifest;
+pub mod args;
+
+/// Version 1 is the original protocol, 2 always quotes names in responses,
+/// 3 adds a correlation ID and a message to errors
+const PROTOCOL_VERSION: u32 = 3;
 
 #[allow(dead_code)]
 pub struct State {
 	info: Arc<crate::ServerInfo>,
 	state: ConnectState,
 	user: Option<Arc<crate::info::user::User>>,
-	addr: Ipv4Addr
+	addr: Ipv4Addr,
+	/// Protocol version agreed on with `hello`
+	version: u32,
+	/// Tag of the request being handled, echoed in its response
+	tag: Option<String>,
+	/// Names in responses are quoted once the client has sent a quoted argument
+	quoting: bool,
+	/// Set for states of channels in multiplexed mode
+	channel: bool,
+	/// Set if the client uses the binary protocol
+	binary: bool
 }
 
 impl State {
@@ -19,42 +48,118 @@ impl State {
 			info,
 			state: ConnectState::Auth,
 			user: None,
-			addr
+			addr,
+			version: 1,
+			tag: None,
+			quoting: false,
+			channel: false,
+			binary: false
 		}
 	}
 
+	/// A state for a channel of a multiplexed connection, sharing the login and protocol settings
+	pub fn channel(&self) -> Self {
+		State {
+			info: self.info.clone(),
+			state: ConnectState::Command,
+			user: self.user.clone(),
+			addr: self.addr,
+			version: self.version,
+			tag: None,
+			quoting: self.quoting,
+			channel: true,
+			binary: false
+		}
+	}
+
+	/// Switches to the binary protocol, which always uses the newest version of responses
+	pub fn use_binary(&mut self) {
+		self.version = PROTOCOL_VERSION;
+		self.quoting = true;
+		self.binary = true;
+	}
+
 	pub fn expects(&self) -> Expectation {
 		use ConnectState::*;
 		use Expectation::*;
 		match self.state {
 			Auth | Command => Line,
+			Upload(ref upload) => Binary(upload.left()),
+			Sync(ref manifest) => Binary(manifest.left()),
+			Mux => Frames,
 			End => Nothing
 		}
 	}
 
 	pub fn end(&self) -> bool {
-		self.state == ConnectState::End
+		matches!(self.state, ConnectState::End)
 	}
 
-	pub async fn next_piece(&mut self, buffer: &[u8]) -> Result<Response> {
-		use ConnectState::*;
+	/// The tag stays set during an upload, the response comes after the data
+	pub fn tag(&self) -> Option<&str> {
+		self.tag.as_deref()
+	}
+
+	pub fn version(&self) -> u32 {
+		self.version
+	}
+
+	pub async fn next_piece(&mut self, buffer: &[u8]) -> Response {
+		let res = self.piece(buffer).await;
+		self.recover(res)
+	}
+
+	/// Handles a request that has already been split into arguments
+	pub async fn next_request(&mut self, args: Args) -> Response {
+		let res = self.request(args).await;
+		self.recover(res)
+	}
+
+	pub async fn next_data(&mut self, buffer: &[u8]) -> Response {
+		let res = self.data(buffer).await;
+		self.recover(res)
+	}
+
+	/// Turns an error into a response, the session goes on.
+	/// Data left of a failed upload is skipped.
+	fn recover(&mut self, res: Result<Response>) -> Response {
+		match res {
+			Ok(res) => res,
+			Err(err) => {
+				let res = Response::failure(err);
+				match self.state {
+					ConnectState::Upload(ref upload) if upload.left() > 0 => {
+						let upload = Upload::discard(upload.info(), upload.left(), res);
+						self.state = ConnectState::Upload(upload);
+						Response::None
+					}
+					// the last piece of data couldn't be written
+					ConnectState::Upload(_) => {
+						self.state = ConnectState::Command;
+						res
+					}
+					_ => res
+				}
+			}
+		}
+	}
+
+	async fn piece(&mut self, buffer: &[u8]) -> Result<Response> {
+		self.tag = None;
 		match String::from_utf8(buffer.into()) {
-			Ok(line) => match self.state {
-				Auth => self.try_login(&line).await,
-				Command => {
-					let (cmd, args) = match line.split_once(' ') {
-						Some(res) => (res.0.trim(), res.1.trim()),
-						None => (line.trim(), "")
-					};
-					match cmd {
-						"" => Ok(Response::None),
-						"list" => self.list(args).await,
-						"download" => self.download(args).await,
-						_ => Ok(Response::NoCmd)
-					}
-				},
-				End => Ok(Response::BadFormat)
-			},
+			Ok(line) => {
+				let args = Args::parse(&line);
+				// `~<tag>` before a request
+				let args = match args.keyword(0).and_then(|first| first.strip_prefix('~')) {
+					Some(tag) if valid_tag(tag) => {
+						self.tag = Some(tag.into());
+						args.tail(1)
+					}
+					Some(_) => return Ok(Response::BadFormat),
+					None => args
+				};
+				self.request(args).await
+			}
 			Err(err) => {
 				error!("Recieved an invalid UTF8 string: {err}");
 				self.state = ConnectState::End;
@@ -63,8 +168,48 @@ impl State {
 		}
 	}
 
-	async fn try_login(&mut self, request: &str) -> Result<Response> {
-		Ok(match request.split_once(' ') {
+	async fn request(&mut self, args: Args) -> Result<Response> {
+		use ConnectState::*;
+		self.quoting |= args.quoted();
+		match self.state {
+			// `hello` and `capabilities` only come after logging in, any user name is allowed
+			Auth => self.try_login(&args).await,
+			Command => {
+				let cmd = args.get(0).unwrap_or("");
+				let args = args.tail(1);
+				match cmd {
+					"" => Ok(Response::None),
+					"hello" => Ok(self.hello(&args)),
+					"capabilities" => Ok(self.capabilities()),
+					"mux" if !self.channel && !self.binary && args.len() == 0 => {
+						self.state = Mux;
+						Ok(Response::Ok(ResponseContent::Empty))
+					}
+					"list" => self.list(&args).await,
+					"stat" => self.stat(&args).await,
+					"tree" | "ls" => self.tree(&args).await,
+					"versions" => self.versions(&args).await,
+					"download" => self.download(&args).await,
+					"upload" => self.upload(&args).await,
+					"resume" => self.resume(&args).await,
+					"delete" => self.delete(&args).await,
+					"move" => self.transfer(&args, false).await,
+					"copy" => self.transfer(&args, true).await,
+					"stash" => self.stash(&args).await,
+					"snapshot" => self.snapshot(&args).await,
+					"diff" => self.diff(&args).await,
+					"usage" => self.usage().await,
+					"sync" => self.sync(&args).await,
+					_ => Ok(Response::NoCmd)
+				}
+			},
+			Upload(_) | Sync(_) | Mux | End => Ok(Response::BadFormat)
+		}
+	}
+
+	async fn try_login(&mut self, args: &Args) -> Result<Response> {
+		// the line is always taken as it was sent, old clients don't escape anything
+		Ok(match args.literal_rest(0).and_then(|line| line.split_once(' ')) {
 			Some((username, password)) => {
 				match self.info.users.get(username).await? {
 					Some(user) => if user.check_password(password) {
@@ -83,70 +228,823 @@ impl State {
 					}
 				}
 			}
-			None => {
+			_ => {
 				self.state = ConnectState::End;
 				Response::BadFormat
 			}
 		})
 	}
 
-	async fn list(&self, args: &str) -> Result<Response> {
-		let args: Vec<&str> = args.split(' ').collect();
-		match args[0] {
-			"stashes" => Ok(Response::Ok(ResponseContent::Lines(
-				self.user.as_ref().unwrap().all_stashes().await?
+	/// `hello <version>`, replies with the version both sides support
+	fn hello(&mut self, args: &Args) -> Response {
+		match (args.get(0).map(str::parse::<u32>), args.len()) {
+			(Some(Ok(version)), 1) if version > 0 => {
+				self.version = version.min(PROTOCOL_VERSION);
+				self.quoting = self.version >= 2;
+				Response::Ok(ResponseContent::Lines(vec![self.version.to_string()]))
+			}
+			_ => Response::BadArgs
+		}
+	}
+
+	/// Features of this server, one per line
+	fn capabilities(&self) -> Response {
+		let mut res: Vec<String> = [
+			"upload", "resume", "delete", "move", "copy", "stat", "hash", "tree", "page",
+			"versions", "snapshots", "diff", "asof", "range", "quota", "quoting", "tags", "mux", "binary", "sync"
+		].iter().map(|name| name.to_string()).collect();
+		if Config::get().compression_level > 0 {
+			res.push("compression".into());
+		}
+		if keys::current().is_some() {
+			res.push("encryption".into());
+		}
+		Response::Ok(ResponseContent::Lines(res))
+	}
+
+	/// Quotes a name for a response line as the client expects it
+	fn name<'a>(&self, name: &'a str) -> std::borrow::Cow<'a, str> {
+		quote(name, self.quoting)
+	}
+
+	async fn list(&self, args: &Args) -> Result<Response> {
+		match (args.get(0), args.get(1)) {
+			(Some("stashes"), None) => Ok(Response::Ok(ResponseContent::Lines(
+				self.user.as_ref().unwrap().all_stashes().await?.iter().map(|name| self.name(name).into_owned()).collect()
 			))),
-			"files" => if args.len() == 2 {
-				match self.user.as_ref().unwrap().get_stash(args[1]).await? {
+			(Some("files"), Some(stash_name)) => {
+				let mut long = false;
+				let mut as_of = None;
+				let mut positional = vec![];
+				for index in 2..args.len() {
+					match args.keyword(index).map(|opt| (opt, opt.strip_prefix("asof=").map(str::parse))) {
+						Some(("long", _)) if !long => long = true,
+						Some((_, Some(Ok(time)))) if as_of.is_none() => as_of = Some(time),
+						Some((_, Some(Err(_)))) => return Ok(Response::BadArgs),
+						_ => positional.push((args.get(index).unwrap(), args.is_quoted(index)))
+					}
+				}
+				let page = match Page::parse(&positional) {
+					Some(page) => page,
+					None => return Ok(Response::BadArgs)
+				};
+				match self.view(stash_name, as_of).await? {
 					Some(stash) => {
-						self.info.audit.log(self.user.as_deref(), self.addr, Event::List, true, Some(args[1])).await?;
+						self.info.audit.log(self.user.as_deref(), self.addr, Event::List, true, Some(stash_name)).await?;
 						Ok(Response::Ok(ResponseContent::Lines(
-							stash.into_iter().map(|s| format!("{} {}", s.0, s.1.update_time())).collect()
+							stash.names(page.prefix())
+								.filter(|name| page.matches(name))
+								.skip(page.offset)
+								.take(page.limit)
+								.filter_map(|name| stash.get(name).map(|file| (name, file)))
+								.map(|(name, file)| if long {
+									format!("{} {}", stat_line(&file), self.name(name))
+								} else {
+									format!("{} {}", self.name(name), file.update_time())
+								}).collect()
 						)))
 					}
 					None => {
-						self.info.audit.log(self.user.as_deref(), self.addr, Event::List, false, Some(args[1])).await?;
+						self.info.audit.log(self.user.as_deref(), self.addr, Event::List, false, Some(stash_name)).await?;
 						Ok(Response::NoStash)
 					}
 				}
-			} else {
-				Ok(Response::BadArgs)
 			}
 			_ => Ok(Response::BadArgs)
 		}
 	}
 
-	async fn download(&self, args: &str) -> Result<Response> {
-		let res = match args.split_once(' ') {
-			Some((stash, path)) => match self.user.as_ref().unwrap().get_stash(stash).await? {
-				Some(stash) => match stash.get(path) {
-					Some(file) => Ok(Response::Ok(ResponseContent::Binary(file.read()))),
+	/// A stash or a snapshot, optionally as it was at some point in time
+	async fn view(&self, name: &str, as_of: Option<u64>) -> Result<Option<Arc<Stash>>> {
+		let stash = self.user.as_ref().unwrap().get_view(name).await?;
+		match (stash, as_of) {
+			(Some(stash), Some(time)) => Ok(Some(Arc::new(stash.as_of(time).await?))),
+			(stash, _) => Ok(stash)
+		}
+	}
+
+	async fn stat(&self, args: &Args) -> Result<Response> {
+		match (args.get(0), args.rest(1)) {
+			(Some(stash), Some(path)) => match self.user.as_ref().unwrap().get_view(stash).await? {
+				Some(stash) => match stash.get(&path) {
+					Some(file) => Ok(Response::Ok(ResponseContent::Lines(vec![stat_line(&file)]))),
+					None => Ok(Response::NoFile)
+				}
+				None => Ok(Response::NoStash)
+			}
+			_ => Ok(Response::BadArgs)
+		}
+	}
+
+	/// Splits selectors off a request on a path in a stash.
+	/// A path that names a file, or a directory if `dir` is set, as a whole is taken as it is,
+	/// old clients send names like `Invoice #42` without quoting them.
+	async fn split_selectors(&self, args: &Args, dir: bool) -> Result<(usize, Selectors)> {
+		let (end, selectors) = Selectors::split(args);
+		if end < args.len() {
+			if let (Some(stash), Some(path)) = (args.get(0), args.rest(1)) {
+				if let Some(stash) = self.user.as_ref().unwrap().get_view(stash).await? {
+					let exists = if dir {
+						stash.children(path.trim_matches('/')).is_some()
+					} else {
+						stash.record(&path).is_some()
+					};
+					if exists {
+						return Ok((args.len(), Selectors::default()));
+					}
+				}
+			}
+		}
+		Ok((end, selectors))
+	}
+
+	/// Immediate children of a directory as `<f|d> <size> <update time> <name>`.
+	/// Directories are implied by file names, their size and time cover everything inside.
+	async fn tree(&self, args: &Args) -> Result<Response> {
+		let (end, selectors) = self.split_selectors(args, true).await?;
+		if selectors.range.is_some() || selectors.revision.is_some() {
+			return Ok(Response::BadArgs);
+		}
+		let stash = match args.get(0).filter(|stash| end > 0 && !stash.is_empty()) {
+			Some(stash) => stash,
+			None => return Ok(Response::BadArgs)
+		};
+		let dir = args.join(1..end).unwrap_or_default();
+		let res = match self.view(stash, selectors.as_of).await? {
+			Some(stash) => match stash.children(dir.trim_matches('/')) {
+				Some(entries) => Response::Ok(ResponseContent::Lines(
+					entries.iter().map(|entry| format!(
+						"{} {} {} {}",
+						if entry.dir { 'd' } else { 'f' },
+						entry.size,
+						entry.update_time,
+						self.name(&if entry.dir { format!("{}/", entry.name) } else { entry.name.clone() })
+					)).collect()
+				)),
+				None => Response::NoFile
+			}
+			None => Response::NoStash
+		};
+		self.info.audit.log(self.user.as_deref(), self.addr, Event::List, matches!(res, Response::Ok(_)), Some(args.raw())).await?;
+		Ok(res)
+	}
+
+	async fn versions(&self, args: &Args) -> Result<Response> {
+		match (args.get(0), args.rest(1)) {
+			(Some(stash), Some(path)) => match self.user.as_ref().unwrap().get_view(stash).await? {
+				Some(stash) => match stash.revisions(&path).await? {
+					Some(revisions) => Ok(Response::Ok(ResponseContent::Lines(
+						revisions.iter().map(|rev| format!(
+							"{} {} {} {}",
+							rev.revision,
+							rev.update_time,
+							rev.size,
+							rev.hash.as_deref().unwrap_or("-")
+						)).collect()
+					))),
 					None => Ok(Response::NoFile)
 				}
 				None => Ok(Response::NoStash)
 			}
-			None => Ok(Response::BadArgs)
+			_ => Ok(Response::BadArgs)
+		}
+	}
+
+	async fn download(&self, args: &Args) -> Result<Response> {
+		let (end, selectors) = self.split_selectors(args, false).await?;
+		let res = match (args.get(0), args.join(1..end)) {
+			(Some(_), Some(_)) if selectors.revision.is_some() && selectors.as_of.is_some() => Ok(Response::BadArgs),
+			(Some(stash), Some(path)) => match self.view(stash, selectors.as_of).await? {
+				Some(stash) => {
+					let file = match selectors.revision {
+						Some(revision) => stash.get_revision(&path, revision).await?,
+						None => stash.get(&path)
+					};
+					match file {
+						Some(file) => {
+							let reader = match selectors.range {
+								Some((offset, len)) => file.open_range(offset, len).await,
+								None => file.open().await.map(Some)
+							};
+							match reader {
+								Ok(Some(reader)) => Ok(Response::Ok(ResponseContent::Binary(reader))),
+								Ok(None) => Ok(Response::BadRange),
+								Err(err) => Ok(Response::logged(
+									"storage",
+									format!("Can't open stored file #{}: {err}", file.id())
+								))
+							}
+						}
+						None => Ok(Response::NoFile)
+					}
+				}
+				None => Ok(Response::NoStash)
+			}
+			_ => Ok(Response::BadArgs)
 		};
 		match res {
 			Ok(ref data) => match data {
-				Response::Ok(_) => self.info.audit.log(self.user.as_deref(), self.addr, Event::Download, true, Some(args)).await?,
-				_ => self.info.audit.log(self.user.as_deref(), self.addr, Event::Download, false, Some(args)).await?
+				Response::Ok(_) => self.info.audit.log(self.user.as_deref(), self.addr, Event::Download, true, Some(args.raw())).await?,
+				_ => self.info.audit.log(self.user.as_deref(), self.addr, Event::Download, false, Some(args.raw())).await?
 			}
 			_ => ()
 		};
 		res
 	}
+
+	async fn upload(&mut self, args: &Args) -> Result<Response> {
+		match parse_upload(args) {
+			Some((stash_name, path, size, update_time)) => {
+				let user = self.user.as_ref().unwrap();
+				let info = format!("{stash_name} {path}");
+				let upload = async {
+					Ok(match user.get_stash(stash_name).await? {
+						Some(stash) if !user.fits_quota(&stash, size, 0).await? => {
+							Upload::discard(&info, size, Response::Quota)
+						}
+						stash => Upload::new(stash_name, stash, &path, size, update_time).await?
+					})
+				}.await;
+				// the data has to be skipped even if the upload can't be started
+				let upload = upload.unwrap_or_else(|err| Upload::discard(&info, size, Response::failure(err)));
+				self.state = ConnectState::Upload(upload);
+				if size == 0 {
+					self.finish_upload().await
+				} else {
+					Ok(Response::None)
+				}
+			}
+			None => {
+				// size of the data is unknown so it can't be skipped
+				self.state = ConnectState::End;
+				Ok(Response::BadArgs)
+			}
+		}
+	}
+
+	async fn resume(&mut self, args: &Args) -> Result<Response> {
+		let user = self.user.as_ref().unwrap();
+		let action = args.get(0).unwrap_or("");
+		let args = args.tail(1);
+		// a single token for everything but `new` and `data`
+		let token = args.get(0).filter(|_| args.len() == 1).unwrap_or("");
+		match action {
+			"new" => {
+				match parse_upload(&args) {
+					Some((stash_name, path, size, update_time)) => match user.get_stash(stash_name).await? {
+						Some(stash) if !user.fits_quota(&stash, size, 0).await? => Ok(Response::Quota),
+						Some(stash) => {
+							let token = self.info.staging.create(&stash, &path, size, update_time).await?;
+							Ok(Response::Ok(ResponseContent::Lines(vec![token])))
+						}
+						None => Ok(Response::NoStash)
+					}
+					None => Ok(Response::BadArgs)
+				}
+			}
+			"status" => match self.info.staging.get(user.id(), token).await? {
+				Some(_) => Ok(Response::Ok(ResponseContent::Lines(vec![
+					Staging::received(token).await?.to_string()
+				]))),
+				None => Ok(Response::NoUpload)
+			}
+			"data" => {
+				let parsed = match (args.get(0), args.get(1), args.get(2), args.len()) {
+					(Some(token), Some(offset), Some(len), 3) => match (offset.parse::<u64>(), len.parse::<u64>()) {
+						(Ok(offset), Ok(len)) => Some((token, offset, len)),
+						_ => None
+					}
+					_ => None
+				};
+				let (token, offset, len) = match parsed {
+					Some(parsed) => parsed,
+					None => {
+						// size of the data is unknown so it can't be skipped
+						self.state = ConnectState::End;
+						return Ok(Response::BadArgs);
+					}
+				};
+				let upload = async {
+					Ok(match self.info.staging.get(user.id(), token).await? {
+						Some(staged) => match user.get_stash(&staged.stash).await? {
+							Some(stash) => {
+								let received = Staging::received(token).await?;
+								if offset != received || offset + len > staged.size {
+									Upload::discard(token, len, Response::BadRange)
+								} else {
+									Upload::resume(token, staged, stash, received, len).await?
+								}
+							}
+							None => Upload::discard(token, len, Response::NoStash)
+						}
+						None => Upload::discard(token, len, Response::NoUpload)
+					})
+				}.await;
+				let upload = upload.unwrap_or_else(|err| Upload::discard(token, len, Response::failure(err)));
+				self.state = ConnectState::Upload(upload);
+				if len == 0 {
+					self.finish_upload().await
+				} else {
+					Ok(Response::None)
+				}
+			}
+			"cancel" => match self.info.staging.get(user.id(), token).await? {
+				Some(_) => {
+					self.info.staging.remove(token).await?;
+					Ok(Response::Ok(ResponseContent::Empty))
+				}
+				None => Ok(Response::NoUpload)
+			}
+			_ => Ok(Response::BadArgs)
+		}
+	}
+
+	async fn delete(&self, args: &Args) -> Result<Response> {
+		let res = match (args.get(0), args.rest(1)) {
+			(Some(stash_name), Some(path)) => {
+				let user = self.user.as_ref().unwrap();
+				match user.get_stash(stash_name).await? {
+					Some(stash) => if stash.remove_file(&path).await? {
+						user.forget_stash(stash_name).await;
+						Response::Ok(ResponseContent::Empty)
+					} else {
+						Response::NoFile
+					}
+					None => Response::NoStash
+				}
+			}
+			_ => Response::BadArgs
+		};
+		let success = matches!(res, Response::Ok(_));
+		self.info.audit.log(self.user.as_deref(), self.addr, Event::DeleteFile, success, Some(args.raw())).await?;
+		Ok(res)
+	}
+
+	/// `move|copy <stash> <path> <dest stash> <dest path>`
+	async fn transfer(&self, args: &Args, copy: bool) -> Result<Response> {
+		let res = match (args.get(0), args.get(1), args.get(2), args.get(3), args.len()) {
+			(Some(stash_name), Some(path), Some(dest_name), Some(dest_path), 4) if !path.is_empty() && !dest_path.is_empty() => {
+				let user = self.user.as_ref().unwrap();
+				match (user.get_stash(stash_name).await?, user.get_stash(dest_name).await?) {
+					(Some(stash), Some(dest)) => match stash.record(path).map(|record| record.size) {
+						Some(size) => {
+							// moving within a stash doesn't change any usage
+							let fits = if copy {
+								user.fits_quota(&dest, size, 0).await?
+							} else {
+								stash_name == dest_name || user.fits_quota(&dest, size, size).await?
+							};
+							if fits {
+								let res = if copy {
+									stash.copy_file(path, &dest, dest_path).await?
+								} else {
+									stash.move_file(path, &dest, dest_path).await?
+								};
+								match res {
+									Transfer::Done => {
+										user.forget_stash(stash_name).await;
+										user.forget_stash(dest_name).await;
+										Response::Ok(ResponseContent::Empty)
+									}
+									Transfer::NoFile => Response::NoFile,
+									Transfer::Exists => Response::Exists
+								}
+							} else {
+								Response::Quota
+							}
+						}
+						None => Response::NoFile
+					}
+					_ => Response::NoStash
+				}
+			}
+			_ => Response::BadArgs
+		};
+		let event = if copy { Event::CopyFile } else { Event::MoveFile };
+		self.info.audit.log(self.user.as_deref(), self.addr, event, matches!(res, Response::Ok(_)), Some(args.raw())).await?;
+		Ok(res)
+	}
+
+	async fn stash(&self, args: &Args) -> Result<Response> {
+		let user = self.user.as_ref().unwrap();
+		// a name with spaces has to be quoted, otherwise only its first word could be used later
+		let name = match args.len() {
+			2 => args.get(1).unwrap_or_default(),
+			_ => ""
+		};
+		match args.get(0).unwrap_or("") {
+			"new" => if valid_name(name) {
+				let res = if user.create_stash(name).await? {
+					Response::Ok(ResponseContent::Empty)
+				} else {
+					Response::Exists
+				};
+				self.info.audit.log(self.user.as_deref(), self.addr, Event::NewStash, matches!(res, Response::Ok(_)), Some(name)).await?;
+				Ok(res)
+			} else {
+				Ok(Response::BadArgs)
+			}
+			"delete" => if !name.is_empty() {
+				let res = if user.delete_stash(name).await? {
+					Response::Ok(ResponseContent::Empty)
+				} else {
+					Response::NoStash
+				};
+				self.info.audit.log(self.user.as_deref(), self.addr, Event::DeleteStash, matches!(res, Response::Ok(_)), Some(name)).await?;
+				Ok(res)
+			} else {
+				Ok(Response::BadArgs)
+			}
+			"rename" => match (args.get(1), args.get(2), args.len()) {
+				(Some(old_name), Some(new_name), 3) if valid_name(new_name) => {
+					let res = match user.rename_stash(old_name, new_name).await? {
+						RenameResult::Done => Response::Ok(ResponseContent::Empty),
+						RenameResult::NoSource => Response::NoStash,
+						RenameResult::Exists => Response::Exists
+					};
+					self.info.audit.log(self.user.as_deref(), self.addr, Event::RenameStash, matches!(res, Response::Ok(_)), Some(args.raw())).await?;
+					Ok(res)
+				}
+				_ => Ok(Response::BadArgs)
+			}
+			_ => Ok(Response::BadArgs)
+		}
+	}
+
+	async fn snapshot(&self, args: &Args) -> Result<Response> {
+		let (action, stash_name, name) = match (args.get(0), args.get(1), args.get(2), args.len()) {
+			(Some(action), Some(stash_name), _, 2) => (action, stash_name, None),
+			(Some(action), Some(stash_name), Some(name), 3) if valid_name(name) => (action, stash_name, Some(name)),
+			_ => return Ok(Response::BadArgs)
+		};
+		let stash = match self.user.as_ref().unwrap().get_stash(stash_name).await? {
+			Some(stash) => stash,
+			None => return Ok(Response::NoStash)
+		};
+		match (action, name) {
+			("create", Some(name)) => {
+				let res = if stash.create_snapshot(name).await? {
+					Response::Ok(ResponseContent::Empty)
+				} else {
+					Response::Exists
+				};
+				self.user.as_ref().unwrap().forget_stash(stash_name).await;
+				let info = format!("{stash_name}:{name}");
+				self.info.audit.log(self.user.as_deref(), self.addr, Event::NewSnapshot, matches!(res, Response::Ok(_)), Some(&info)).await?;
+				Ok(res)
+			}
+			("list", None) => Ok(Response::Ok(ResponseContent::Lines(
+				stash.snapshots().await?.into_iter().map(|(time, name)| format!("{time} {}", self.name(&name))).collect()
+			))),
+			("delete", Some(name)) => {
+				let res = if stash.delete_snapshot(name).await? {
+					Response::Ok(ResponseContent::Empty)
+				} else {
+					Response::NoSnapshot
+				};
+				let info = format!("{stash_name}:{name}");
+				self.info.audit.log(self.user.as_deref(), self.addr, Event::DeleteSnapshot, matches!(res, Response::Ok(_)), Some(&info)).await?;
+				Ok(res)
+			}
+			_ => Ok(Response::BadArgs)
+		}
+	}
+
+	/// Compares two snapshots, or a snapshot with the live stash if the second one is omitted
+	async fn diff(&self, args: &Args) -> Result<Response> {
+		let user = self.user.as_ref().unwrap();
+		let (stash_name, from, to) = match (args.get(0), args.get(1), args.get(2), args.len()) {
+			(Some(stash_name), Some(from), _, 2) => (stash_name, from, None),
+			(Some(stash_name), Some(from), Some(to), 3) => (stash_name, from, Some(to)),
+			_ => return Ok(Response::BadArgs)
+		};
+		let res = match user.get_stash(stash_name).await? {
+			Some(stash) => {
+				let older = user.get_snapshot(&stash, from).await?;
+				let newer = match to {
+					Some(to) => user.get_snapshot(&stash, to).await?,
+					None => Some(stash.clone())
+				};
+				match (older, newer) {
+					(Some(older), Some(newer)) => Response::Ok(ResponseContent::Lines(
+						older.diff(&newer).into_iter().map(|change| match change {
+							Change::Added(name) => format!("+ {}", self.name(name)),
+							Change::Removed(name) => format!("- {}", self.name(name)),
+							Change::Modified(name) => format!("~ {}", self.name(name))
+						}).collect()
+					)),
+					_ => Response::NoSnapshot
+				}
+			}
+			None => Response::NoStash
+		};
+		self.info.audit.log(self.user.as_deref(), self.addr, Event::List, matches!(res, Response::Ok(_)), Some(args.raw())).await?;
+		Ok(res)
+	}
+
+	/// The first line is the total for the user, then `<used> <quota> <files> <stash>` for every stash.
+	/// Quota is `-` if there's no limit.
+	async fn usage(&self) -> Result<Response> {
+		let user = self.user.as_ref().unwrap();
+		let quota = |quota: Option<u64>| quota.map(|quota| quota.to_string()).unwrap_or_else(|| "-".into());
+		let stashes = user.usage().await?;
+		let used = user.used().await?;
+		let files = stashes.iter().map(|stash| stash.files).sum::<u64>();
+		let mut lines = vec![format!("{used} {} {files}", quota(user.quota().await?))];
+		lines.extend(stashes.iter().map(|stash| format!(
+			"{} {} {} {}",
+			stash.used,
+			quota(stash.quota),
+			stash.files,
+			self.name(&stash.name)
+		)));
+		Ok(Response::Ok(ResponseContent::Lines(lines)))
+	}
+
+	/// `sync <stash> <size>`, followed by a manifest of `size` bytes.
+	/// Replies with the paths that have to be uploaded or deleted in the same format as `diff`.
+	/// A manifest longer than the configured limit is skipped and rejected.
+	async fn sync(&mut self, args: &Args) -> Result<Response> {
+		let (stash_name, size) = match (args.get(0), args.get(1).map(str::parse::<u64>), args.len()) {
+			(Some(stash_name), Some(Ok(size)), 2) => (stash_name, size),
+			_ => {
+				// size of the manifest is unknown so it can't be skipped
+				self.state = ConnectState::End;
+				return Ok(Response::BadArgs);
+			}
+		};
+		// the manifest has to be skipped even if there's nothing to compare it with
+		let stash = if size > Config::get().max_manifest_size {
+			Err(Response::BadArgs)
+		} else {
+			match self.user.as_ref().unwrap().get_view(stash_name).await {
+				Ok(Some(stash)) => Ok(stash),
+				Ok(None) => Err(Response::NoStash),
+				Err(err) => Err(Response::failure(err))
+			}
+		};
+		self.state = ConnectState::Sync(Manifest::new(stash_name, stash, size));
+		if size == 0 {
+			self.finish_sync().await
+		} else {
+			Ok(Response::None)
+		}
+	}
+
+	async fn data(&mut self, buffer: &[u8]) -> Result<Response> {
+		match self.state {
+			ConnectState::Upload(ref mut upload) => {
+				upload.write(buffer).await?;
+				if upload.left() == 0 {
+					self.finish_upload().await
+				} else {
+					Ok(Response::None)
+				}
+			}
+			ConnectState::Sync(ref mut manifest) => {
+				manifest.write(buffer);
+				if manifest.left() == 0 {
+					self.finish_sync().await
+				} else {
+					Ok(Response::None)
+				}
+			}
+			_ => Ok(Response::BadFormat)
+		}
+	}
+
+	async fn finish_sync(&mut self) -> Result<Response> {
+		match std::mem::replace(&mut self.state, ConnectState::Command) {
+			ConnectState::Sync(manifest) => {
+				let stash_name = manifest.stash_name().to_string();
+				let res = match manifest.finish() {
+					Ok(changes) => Response::Ok(ResponseContent::Lines(
+						changes.iter().map(|(sign, name)| format!("{sign} {}", self.name(name))).collect()
+					)),
+					Err(res) => res
+				};
+				self.info.audit.log(self.user.as_deref(), self.addr, Event::List, matches!(res, Response::Ok(_)), Some(&stash_name)).await?;
+				Ok(res)
+			}
+			state => {
+				self.state = state;
+				Ok(Response::BadFormat)
+			}
+		}
+	}
+
+	async fn finish_upload(&mut self) -> Result<Response> {
+		match std::mem::replace(&mut self.state, ConnectState::Command) {
+			ConnectState::Upload(upload) => {
+				let stash_name = upload.stash_name().to_string();
+				let info = upload.info().to_string();
+				let token = upload.token().map(String::from);
+				// other uploads may have finished since `resume new`, the staged data is kept
+				// so the upload can be finished again with no data once there's space
+				if let Some((stash, size)) = upload.completes() {
+					if !self.user.as_ref().unwrap().fits_quota(stash, size, 0).await? {
+						self.info.audit.log(self.user.as_deref(), self.addr, Event::Upload, false, Some(&info)).await?;
+						return Ok(Response::Quota);
+					}
+				}
+				match upload.finish().await? {
+					Finish::Stored => {
+						if let Some(token) = token {
+							self.info.staging.remove(&token).await?;
+						}
+						self.info.audit.log(self.user.as_deref(), self.addr, Event::Upload, true, Some(&info)).await?;
+						self.user.as_ref().unwrap().forget_stash(&stash_name).await;
+						Ok(Response::Ok(ResponseContent::Empty))
+					}
+					Finish::Partial => Ok(Response::Ok(ResponseContent::Empty)),
+					Finish::Rejected(res) => {
+						self.info.audit.log(self.user.as_deref(), self.addr, Event::Upload, false, Some(&info)).await?;
+						Ok(res)
+					}
+				}
+			}
+			state => {
+				self.state = state;
+				Ok(Response::BadFormat)
+			}
+		}
+	}
+}
+
+/// Parses `<stash> <path> <size> <update time>`
+fn parse_upload(args: &Args) -> Option<(&str, std::borrow::Cow<'_, str>, u64, u64)> {
+	let len = args.len();
+	if len < 3 {
+		return None;
+	}
+	match (args.get(0), args.join(1..len - 2), args.get(len - 2)?.parse(), args.get(len - 1)?.parse()) {
+		(Some(stash), Some(path), Ok(size), Ok(update_time)) => Some((stash, path, size, update_time)),
+		_ => None
+	}
+}
+
+/// `<update time> <size> <hash>`, hash is `-` if it wasn't recorded
+fn stat_line(file: &File) -> String {
+	format!("{} {} {}", file.update_time(), file.size(), file.hash().unwrap_or("-"))
+}
+
+/// Optional arguments at the end of a `download` command
+#[derive(Default)]
+struct Selectors {
+	/// `@<offset>[+<length>]`
+	range: Option<(u64, Option<u64>)>,
+	/// `#<revision>`
+	revision: Option<u64>,
+	/// `asof=<unix time>`
+	as_of: Option<u64>
+}
+
+impl Selectors {
+	/// Splits selectors off the end of arguments, returns the number of arguments before them.
+	/// Anything that doesn't parse as a selector is left as a part of a path.
+	fn split(args: &Args) -> (usize, Self) {
+		let mut res = Selectors::default();
+		let mut end = args.len();
+		while end > 1 {
+			let token = match args.keyword(end - 1) {
+				Some(token) => token,
+				None => break
+			};
+			let parsed = if let Some(range) = token.strip_prefix('@').filter(|_| res.range.is_none()) {
+				parse_range(range).map(|range| res.range = Some(range))
+			} else if let Some(revision) = token.strip_prefix('#').filter(|_| res.revision.is_none()) {
+				revision.parse().ok().map(|revision| res.revision = Some(revision))
+			} else if let Some(time) = token.strip_prefix("asof=").filter(|_| res.as_of.is_none()) {
+				time.parse().ok().map(|time| res.as_of = Some(time))
+			} else {
+				None
+			};
+			if parsed.is_none() {
+				break;
+			}
+			end -= 1;
+		}
+		(end, res)
+	}
+}
+
+/// Part of a file listing, `[prefix|glob] [offset] [limit]`
+struct Page<'a> {
+	pattern: Option<&'a str>,
+	offset: usize,
+	limit: usize
+}
+
+impl<'a> Page<'a> {
+	/// A lone number is an offset unless it's quoted, with three arguments the first one
+	/// is always a pattern. Arguments come with a flag telling if they were quoted.
+	fn parse(args: &[(&'a str, bool)]) -> Option<Self> {
+		let number = |(arg, quoted): &(&str, bool)| !quoted && arg.parse::<usize>().is_ok();
+		let (pattern, numbers) = match args {
+			[(pattern, _), (offset, _), (limit, _)] => (Some(*pattern), [Some(*offset), Some(*limit)]),
+			[first, (second, _)] if number(first) => (None, [Some(first.0), Some(*second)]),
+			[(pattern, _), (offset, _)] => (Some(*pattern), [Some(*offset), None]),
+			[first] if number(first) => (None, [Some(first.0), None]),
+			[(pattern, _)] => (Some(*pattern), [None, None]),
+			[] => (None, [None, None]),
+			_ => return None
+		};
+		let offset = match numbers[0] {
+			Some(offset) => offset.parse().ok()?,
+			None => 0
+		};
+		let limit = match numbers[1] {
+			Some(limit) => limit.parse().ok()?,
+			None => usize::MAX
+		};
+		Some(Page { pattern, offset, limit })
+	}
+
+	/// The part of the pattern every matching name starts with
+	fn prefix(&self) -> &'a str {
+		match self.pattern {
+			Some(pattern) => &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())],
+			None => ""
+		}
+	}
+
+	/// Patterns with `*` or `?` are globs, anything else is a prefix
+	fn matches(&self, name: &str) -> bool {
+		match self.pattern {
+			Some(pattern) if pattern.contains(['*', '?']) => glob_match(pattern, name),
+			Some(prefix) => name.starts_with(prefix),
+			None => true
+		}
+	}
+}
+
+/// `*` matches any number of characters including slashes, `?` matches one
+fn glob_match(pattern: &str, name: &str) -> bool {
+	let pattern: Vec<char> = pattern.chars().collect();
+	let name: Vec<char> = name.chars().collect();
+	let (mut p, mut n) = (0, 0);
+	// position of the last star and where it started matching
+	let mut star = None;
+	while n < name.len() {
+		match pattern.get(p) {
+			Some('*') => {
+				star = Some((p, n));
+				p += 1;
+			}
+			Some(ch) if *ch == '?' || *ch == name[n] => {
+				p += 1;
+				n += 1;
+			}
+			_ => match star {
+				Some((star_p, star_n)) => {
+					p = star_p + 1;
+					n = star_n + 1;
+					star = Some((star_p, star_n + 1));
+				}
+				None => return false
+			}
+		}
+	}
+	pattern[p..].iter().all(|ch| *ch == '*')
+}
+
+/// Parses `<offset>` or `<offset>+<length>`
+fn parse_range(range: &str) -> Option<(u64, Option<u64>)> {
+	match range.split_once('+') {
+		Some((offset, len)) => match (offset.parse(), len.parse()) {
+			(Ok(offset), Ok(len)) => Some((offset, Some(len))),
+			_ => None
+		}
+		None => range.parse().ok().map(|offset| (offset, None))
+	}
+}
+
+/// Tags are short and can't need quoting
+fn valid_tag(tag: &str) -> bool {
+	!tag.is_empty() && tag.len() <= 64 && tag.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
+}
+
+/// A colon separates a stash from a snapshot.
+/// Names with spaces can only be used by clients that quote arguments.
+fn valid_name(name: &str) -> bool {
+	!name.is_empty() && name.len() <= 80 && !name.contains(':') && !name.contains(['\n', '\r'])
 }
 
-#[derive(PartialEq)]
 enum ConnectState {
 	Auth,
 	Command,
+	Upload(Upload),
+	/// A manifest for `sync` is being received
+	Sync(Manifest),
+	/// Everything after the `mux` command is framed
+	Mux,
 	End
 }
 
 pub enum Expectation {
 	Line,
+	Binary(u64),
+	Frames,
 	Nothing
 }
 
@@ -158,48 +1056,208 @@ pub enum Response {
 	NoCmd,
 	BadArgs,
 	NoStash,
-	NoFile
+	NoFile,
+	Exists,
+	BadRange,
+	NoUpload,
+	NoSnapshot,
+	Quota,
+	Busy,
+	/// An internal error, logged with an ID that is sent to the client as well
+	Failure {
+		code: &'static str,
+		id: String
+	}
 }
 
 pub enum ResponseContent {
 	Empty,
 	Lines(Vec<String>),
-	Binary(Vec<u8>)
+	Binary(Reader)
 }
 
-impl Into<Vec<u8>> for &Response {
-	fn into(self) -> Vec<u8> {
+impl Response {
+	/// Logs an error that isn't the client's fault
+	pub fn logged(code: &'static str, msg: String) -> Self {
+		let mut id = [0; 4];
+		let id = match openssl::rand::rand_bytes(&mut id) {
+			Ok(_) => id.iter().map(|byte| format!("{byte:02x}")).collect::<String>(),
+			Err(_) => "-".into()
+		};
+		error!("[{id}] {msg}");
+		Response::Failure { code, id }
+	}
+
+	pub fn failure(err: anyhow::Error) -> Self {
+		let code = if err.downcast_ref::<sqlx::Error>().is_some() {
+			"database"
+		} else if err.downcast_ref::<std::io::Error>().is_some() {
+			"storage"
+		} else {
+			"server"
+		};
+		Response::logged(code, format!("Request failed: {err}"))
+	}
+
+	/// Code of an error response as it is sent after `err:`
+	pub fn error_code(&self) -> Option<&'static str> {
 		use Response::*;
+		match self {
+			None | Ok(_) => Option::None,
+			BadFormat => Some("format"),
+			NoAuth => Some("auth"),
+			NoCmd => Some("nocommand"),
+			BadArgs => Some("badargs"),
+			NoStash => Some("nostash"),
+			NoFile => Some("nofile"),
+			Exists => Some("exists"),
+			BadRange => Some("range"),
+			NoUpload => Some("noupload"),
+			NoSnapshot => Some("nosnapshot"),
+			Quota => Some("quota"),
+			Busy => Some("busy"),
+			Failure { code, .. } => Some(code)
+		}
+	}
+
+	/// Code for clients older than version 3, which only know some of the internal errors
+	fn legacy_code(&self) -> &'static str {
+		match self {
+			Response::Failure { code: "storage", .. } => "storage",
+			Response::Failure { .. } => "server",
+			res => res.error_code().unwrap_or("server")
+		}
+	}
+
+	pub fn correlation_id(&self) -> Option<&str> {
+		match self {
+			Response::Failure { id, .. } => Some(id),
+			_ => Option::None
+		}
+	}
+
+	pub fn message(&self) -> &'static str {
+		use Response::*;
+		match self {
+			None | Ok(_) => "",
+			BadFormat => "malformed request",
+			NoAuth => "authentication failed",
+			NoCmd => "unknown command",
+			BadArgs => "invalid arguments",
+			NoStash => "no such stash",
+			NoFile => "no such file",
+			Exists => "already exists",
+			BadRange => "range is out of bounds",
+			NoUpload => "no such upload",
+			NoSnapshot => "no such snapshot",
+			Quota => "quota exceeded",
+			Busy => "channel is busy",
+			Failure { code: "storage", .. } => "storage unavailable",
+			Failure { code: "database", .. } => "database error",
+			Failure { .. } => "internal server error"
+		}
+	}
+
+	/// The response as it is sent in the line protocol.
+	/// Only a header is produced for binary content, the data itself is sent separately.
+	/// Errors are `err:<code> <correlation id|-> <message>` since version 3.
+	pub fn header(&self, version: u32) -> Vec<u8> {
 		use ResponseContent::*;
 		match self {
-			None => vec![],
-			Ok(content) => match content {
+			Response::None => vec![],
+			Response::Ok(content) => match content {
 				Empty => Vec::from(&b"ok:0\n"[..]),
+				// names in the lines are quoted, so none of them contains a line break
 				Lines(lines) => {
-					let fake_lines = lines.iter().fold(
-						0,
-						|count, line| count + line.chars().fold(
-							0,
-							|count, ch| count + if ch == '\n' { 1 } else { 0 }
-						)
-					);
-
-					let res = format!("ok:l{}\n", lines.len() + fake_lines);
+					let res = format!("ok:l{}\n", lines.len());
 					let res = lines.iter().fold(res, |res, line| res + line + "\n");
 					Vec::from(res.as_bytes())
 				}
-				Binary(data) => {
-					let mut res = Vec::from(format!("ok:b{}\n", data.len()).as_bytes());
-					res.extend(data);
-					res
-				}
+				Binary(reader) => Vec::from(format!("ok:b{}\n", reader.len()).as_bytes())
 			},
-			BadFormat => Vec::from(&b"err:format\n"[..]),
-			NoAuth => Vec::from(&b"err:auth\n"[..]),
-			NoCmd => Vec::from(&b"err:nocommand\n"[..]),
-			BadArgs => Vec::from(&b"err:badargs\n"[..]),
-			NoStash => Vec::from(&b"err:nostash\n"[..]),
-			NoFile => Vec::from(&b"err:nofile\n"[..])
+			res if version < 3 => Vec::from(format!("err:{}\n", res.legacy_code()).as_bytes()),
+			res => Vec::from(format!(
+				"err:{} {} {}\n",
+				res.error_code().unwrap_or("server"),
+				res.correlation_id().unwrap_or("-"),
+				res.message()
+			).as_bytes())
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn glob() {
+		assert!(glob_match("*", ""));
+		assert!(glob_match("*", "any/thing"));
+		assert!(glob_match("*.txt", "dir/notes.txt"));
+		assert!(!glob_match("*.txt", "notes.txt.bak"));
+		assert!(glob_match("a?c", "abc"));
+		assert!(!glob_match("a?c", "ac"));
+		assert!(glob_match("a*b*c", "aXbYbZc"));
+		assert!(!glob_match("a*b*c", "aXbYbZ"));
+		assert!(glob_match("**x", "x"));
+		assert!(glob_match("a*", "a"));
+		assert!(!glob_match("", "a"));
+		assert!(glob_match("?ü*", "äü"));
+		assert!(glob_match("*aab", "aaaab"));
+	}
+
+	#[test]
+	fn page() {
+		let parse = |args: &[(&'static str, bool)]| Page::parse(args).map(|page| (page.pattern, page.offset, page.limit));
+		assert_eq!(parse(&[]), Some((None, 0, usize::MAX)));
+		assert_eq!(parse(&[("10", false)]), Some((None, 10, usize::MAX)));
+		assert_eq!(parse(&[("10", false), ("5", false)]), Some((None, 10, 5)));
+		assert_eq!(parse(&[("docs/", false)]), Some((Some("docs/"), 0, usize::MAX)));
+		assert_eq!(parse(&[("*.txt", false), ("3", false)]), Some((Some("*.txt"), 3, usize::MAX)));
+		assert_eq!(parse(&[("2023", false), ("10", false), ("5", false)]), Some((Some("2023"), 10, 5)));
+		// quoting makes a number a prefix
+		assert_eq!(parse(&[("2023", true)]), Some((Some("2023"), 0, usize::MAX)));
+		assert_eq!(parse(&[("2023", true), ("10", false)]), Some((Some("2023"), 10, usize::MAX)));
+		assert_eq!(parse(&[("docs/", false), ("x", false)]), None);
+		assert_eq!(parse(&[("a", false), ("1", false), ("2", false), ("3", false)]), None);
+
+		let page = Page { pattern: Some("docs/"), offset: 0, limit: 1 };
+		assert!(page.matches("docs/a") && !page.matches("src/docs/a"));
+		let page = Page { pattern: Some("*/a"), offset: 0, limit: 1 };
+		assert!(page.matches("docs/a") && !page.matches("docs/b"));
+	}
+
+	#[test]
+	fn range() {
+		assert_eq!(parse_range("0"), Some((0, None)));
+		assert_eq!(parse_range("100+20"), Some((100, Some(20))));
+		assert_eq!(parse_range("100+"), None);
+		assert_eq!(parse_range("+20"), None);
+		assert_eq!(parse_range("-1"), None);
+		assert_eq!(parse_range("1+2+3"), None);
+		assert_eq!(parse_range(""), None);
+	}
+
+	#[test]
+	fn error_headers() {
+		let database = Response::Failure { code: "database", id: "0a1b2c3d".into() };
+		let storage = Response::Failure { code: "storage", id: "0a1b2c3d".into() };
+		assert_eq!(Response::BadArgs.header(2), b"err:badargs\n");
+		assert_eq!(Response::BadArgs.header(3), b"err:badargs - invalid arguments\n");
+		assert_eq!(database.header(2), b"err:server\n");
+		assert_eq!(database.header(3), b"err:database 0a1b2c3d database error\n");
+		assert_eq!(storage.header(2), b"err:storage\n");
+		assert_eq!(storage.header(3), b"err:storage 0a1b2c3d storage unavailable\n");
+		assert!(Response::None.header(3).is_empty());
+	}
+
+	#[test]
+	fn line_count() {
+		let lines = ["plain", "line\nbreak", "two\n\nbreaks"].iter().map(|name| quote(name, false).into_owned()).collect();
+		let header = String::from_utf8(Response::Ok(ResponseContent::Lines(lines)).header(3)).unwrap();
+		let mut lines = header.lines();
+		assert_eq!(lines.next(), Some("ok:l3"));
+		assert_eq!(lines.collect::<Vec<_>>(), ["plain", r#""line\nbreak""#, r#""two\n\nbreaks""#]);
+	}
+}
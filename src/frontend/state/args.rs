@@ -0,0 +1,270 @@
+use std::{borrow::Cow, ops::Range};
+
+/// Arguments of a command.
+/// They are separated by single spaces. An argument starting with `"` runs until the closing quote
+/// and may contain spaces and escapes: `\\`, `\"`, `\n`, `\r` and `\t`.
+/// Anything else is taken as is, so lines without quotes mean what they always did.
+/// That includes arguments that only start with a quote but aren't closed right before a space
+/// or the end of the line.
+pub struct Args {
+	/// The line as it was received
+	line: String,
+	/// Offset of the first argument in `line`
+	lead: usize,
+	raw: String,
+	tokens: Vec<Token>
+}
+
+struct Token {
+	text: String,
+	quoted: bool,
+	/// Byte offset of the argument in the line
+	start: usize
+}
+
+impl Args {
+	pub fn parse(line: &str) -> Self {
+		let raw = line.trim();
+		let mut tokens = vec![];
+		let mut rest = raw;
+		let mut start = 0;
+		while !raw.is_empty() {
+			let (token, len) = match rest.strip_prefix('"').and_then(unquote) {
+				Some((text, len)) => (Token { text, quoted: true, start }, len),
+				None => {
+					let len = rest.find(' ').unwrap_or(rest.len());
+					(Token { text: rest[..len].into(), quoted: false, start }, len)
+				}
+			};
+			tokens.push(token);
+			if len == rest.len() {
+				break;
+			}
+			rest = &rest[len + 1..];
+			start += len + 1;
+		}
+		Args {
+			line: line.into(),
+			lead: line.len() - line.trim_start().len(),
+			raw: raw.into(),
+			tokens
+		}
+	}
+
+	/// Arguments that were sent separately, as in the binary protocol.
+	/// None of them counts as quoted, so options are recognized as usual.
+	pub fn from_list(list: Vec<String>) -> Self {
+		let mut start = 0;
+		let tokens = list.into_iter().map(|text| {
+			let token = Token { start, text, quoted: false };
+			start += token.text.len() + 1;
+			token
+		}).collect::<Vec<_>>();
+		let raw = tokens.iter().map(|token| token.text.as_str()).collect::<Vec<_>>().join(" ");
+		Args {
+			line: raw.clone(),
+			lead: 0,
+			raw,
+			tokens
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.tokens.len()
+	}
+
+	pub fn get(&self, index: usize) -> Option<&str> {
+		self.tokens.get(index).map(|token| token.text.as_str())
+	}
+
+	pub fn is_quoted(&self, index: usize) -> bool {
+		self.tokens.get(index).is_some_and(|token| token.quoted)
+	}
+
+	/// Options and selectors are never taken from quoted arguments
+	pub fn keyword(&self, index: usize) -> Option<&str> {
+		self.tokens.get(index).filter(|token| !token.quoted).map(|token| token.text.as_str())
+	}
+
+	/// Arguments in the range joined with spaces, old clients send paths with spaces this way.
+	/// Returns `None` for an empty range.
+	pub fn join(&self, range: Range<usize>) -> Option<Cow<'_, str>> {
+		match self.tokens.get(range) {
+			Some([]) | None => None,
+			Some([token]) => Some(Cow::Borrowed(&token.text)),
+			Some(tokens) => Some(Cow::Owned(
+				tokens.iter().map(|token| token.text.as_str()).collect::<Vec<_>>().join(" ")
+			))
+		}
+	}
+
+	/// Everything from the argument at `index` on
+	pub fn rest(&self, index: usize) -> Option<Cow<'_, str>> {
+		self.join(index..self.len())
+	}
+
+	/// The line from the argument at `index` on exactly as it was received, spaces included
+	pub fn literal_rest(&self, index: usize) -> Option<&str> {
+		self.tokens.get(index).map(|token| &self.line[self.lead + token.start..])
+	}
+
+	/// Arguments from `index` on, used to pass them to a subcommand
+	pub fn tail(&self, index: usize) -> Args {
+		let offset = self.tokens.get(index).map(|token| token.start).unwrap_or(self.raw.len());
+		Args {
+			line: self.line[self.lead + offset..].into(),
+			lead: 0,
+			raw: self.raw[offset..].into(),
+			tokens: self.tokens.iter().skip(index).map(|token| Token {
+				text: token.text.clone(),
+				quoted: token.quoted,
+				start: token.start - offset
+			}).collect()
+		}
+	}
+
+	/// The line as it was received, used for logging
+	pub fn raw(&self) -> &str {
+		&self.raw
+	}
+
+	pub fn quoted(&self) -> bool {
+		self.tokens.iter().any(|token| token.quoted)
+	}
+}
+
+/// Reads a quoted argument after its opening quote.
+/// Returns its text and length with both quotes, or `None` if it isn't a proper quoted argument.
+fn unquote(quoted: &str) -> Option<(String, usize)> {
+	let mut text = String::new();
+	let mut chars = quoted.char_indices();
+	let end = loop {
+		match chars.next()? {
+			(pos, '"') => break pos + 2,
+			(_, '\\') => text.push(match chars.next()?.1 {
+				'\\' => '\\',
+				'"' => '"',
+				'n' => '\n',
+				'r' => '\r',
+				't' => '\t',
+				_ => return None
+			}),
+			(_, ch) => text.push(ch)
+		}
+	};
+	match quoted[end - 1..].chars().next() {
+		None | Some(' ') => Some((text, end)),
+		_ => None
+	}
+}
+
+/// Prepares a name to be a part of a response line.
+/// Line breaks are always escaped to keep the framing. Names with spaces are only quoted
+/// in `full` mode, clients that never sent a quote expect them as they are.
+pub fn quote(text: &str, full: bool) -> Cow<'_, str> {
+	let needed = text.contains(['\n', '\r'])
+		|| full && (text.is_empty() || text.starts_with('"') || text.contains([' ', '\t']));
+	if !needed {
+		return Cow::Borrowed(text);
+	}
+	let mut res = String::with_capacity(text.len() + 2);
+	res.push('"');
+	for ch in text.chars() {
+		match ch {
+			'\\' => res.push_str("\\\\"),
+			'"' => res.push_str("\\\""),
+			'\n' => res.push_str("\\n"),
+			'\r' => res.push_str("\\r"),
+			'\t' => res.push_str("\\t"),
+			ch => res.push(ch)
+		}
+	}
+	res.push('"');
+	Cow::Owned(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn texts(args: &Args) -> Vec<&str> {
+		(0..args.len()).map(|index| args.get(index).unwrap()).collect()
+	}
+
+	#[test]
+	fn plain_arguments() {
+		let args = Args::parse("download  stash some/file ");
+		assert_eq!(texts(&args), ["download", "", "stash", "some/file"]);
+		assert!(!args.quoted());
+		assert_eq!(args.rest(2).unwrap(), "stash some/file");
+		assert_eq!(args.raw(), "download  stash some/file");
+		assert_eq!(Args::parse("  ").len(), 0);
+	}
+
+	#[test]
+	fn quoted_arguments() {
+		let args = Args::parse(r#"download "my stash" "a \"b\"\\c\n\r\t" long"#);
+		assert_eq!(texts(&args), ["download", "my stash", "a \"b\"\\c\n\r\t", "long"]);
+		assert!(args.quoted());
+		assert!(args.is_quoted(1) && !args.is_quoted(3));
+		assert_eq!(args.keyword(1), None);
+		assert_eq!(args.keyword(3), Some("long"));
+		assert_eq!(texts(&Args::parse(r#""" x"#)), ["", "x"]);
+	}
+
+	#[test]
+	fn broken_quotes_are_literal() {
+		for line in [r#"download s "draft".txt"#, r#"download s "open"#, r#"download s "bad \q""#] {
+			let args = Args::parse(line);
+			assert!(!args.quoted(), "{line}");
+			assert_eq!(args.rest(0).unwrap(), line);
+		}
+		let args = Args::parse(r#"alice "abc"def"#);
+		assert_eq!(texts(&args), ["alice", r#""abc"def"#]);
+	}
+
+	#[test]
+	fn literal_rest_keeps_spaces() {
+		let args = Args::parse("alice  pass word  ");
+		assert_eq!(args.literal_rest(1), Some(" pass word  "));
+		assert_eq!(args.tail(1).literal_rest(0), Some(" pass word  "));
+		assert_eq!(Args::parse(" alice pw").literal_rest(1), Some("pw"));
+		assert_eq!(args.literal_rest(4), None);
+	}
+
+	#[test]
+	fn tail_keeps_tokens() {
+		let args = Args::parse(r#"stash rename "a b" c"#).tail(1);
+		assert_eq!(texts(&args), ["rename", "a b", "c"]);
+		assert_eq!(args.raw(), r#"rename "a b" c"#);
+		let rest = args.tail(1);
+		assert!(rest.is_quoted(0));
+		assert_eq!(rest.raw(), r#""a b" c"#);
+		assert_eq!(args.tail(3).len(), 0);
+	}
+
+	#[test]
+	fn list_arguments() {
+		let args = Args::from_list(vec!["upload".into(), "a b".into(), "".into()]);
+		assert_eq!(texts(&args), ["upload", "a b", ""]);
+		assert!(!args.quoted());
+		assert_eq!(args.keyword(1), Some("a b"));
+		assert_eq!(args.literal_rest(1), Some("a b "));
+	}
+
+	#[test]
+	fn quote_round_trip() {
+		let names = [
+			"plain", "with space", "", "\"leading", "in\"side", "back\\slash", "line\nbreak",
+			"tab\there", "carriage\rreturn", "ünïcödé name", "trailing ", " leading"
+		];
+		for name in names {
+			let quoted = quote(name, true);
+			let args = Args::parse(&format!("x {quoted} y"));
+			assert_eq!(texts(&args), ["x", name, "y"], "{quoted}");
+		}
+		assert_eq!(quote("with space", false), "with space");
+		assert_eq!(quote("line\nbreak", false), r#""line\nbreak""#);
+		assert!(matches!(quote("plain", true), Cow::Borrowed(_)));
+	}
+}
@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+use async_std::sync::Arc;
+use crate::info::{stash::Stash, file::Record};
+use super::{Response, args::Args};
+
+/// Longest manifest entry that is accepted
+const MAX_ENTRY_LEN: usize = 64 * 1024;
+
+/// A list of files on the client that is being received to be compared with a stash.
+/// Every entry is a line of `<size> <update time> <hash|-> <path>`, the path may be quoted.
+pub struct Manifest {
+	stash_name: String,
+	/// The response to send instead of changes if there's nothing to compare with
+	stash: Result<Arc<Stash>, Response>,
+	left: u64,
+	/// Start of an entry that hasn't been received completely
+	partial: Vec<u8>,
+	/// Paths that are new or differ from the stash, with `+` or `~`
+	changes: Vec<(char, String)>,
+	/// Every path in the manifest so far
+	seen: HashSet<String>,
+	/// Set once a broken entry is found, the rest of the manifest is skipped
+	broken: bool
+}
+
+impl Manifest {
+	pub fn new(stash_name: &str, stash: Result<Arc<Stash>, Response>, size: u64) -> Self {
+		Manifest {
+			stash_name: stash_name.into(),
+			stash,
+			left: size,
+			partial: vec![],
+			changes: vec![],
+			seen: HashSet::new(),
+			broken: false
+		}
+	}
+
+	pub fn stash_name(&self) -> &str {
+		&self.stash_name
+	}
+
+	pub fn left(&self) -> u64 {
+		self.left
+	}
+
+	pub fn write(&mut self, data: &[u8]) {
+		self.left -= data.len() as u64;
+		if self.broken || self.stash.is_err() {
+			return;
+		}
+		let mut rest = data;
+		while let Some(nl_pos) = rest.iter().position(|ch| *ch == b'\n') {
+			self.partial.extend_from_slice(&rest[..nl_pos]);
+			let entry = std::mem::take(&mut self.partial);
+			self.entry(&entry);
+			if self.broken {
+				return;
+			}
+			rest = &rest[nl_pos + 1..];
+		}
+		self.partial.extend_from_slice(rest);
+		if self.partial.len() > MAX_ENTRY_LEN {
+			self.broken = true;
+		}
+	}
+
+	fn entry(&mut self, line: &[u8]) {
+		let stash = match self.stash {
+			Ok(ref stash) => stash,
+			Err(_) => return
+		};
+		let args = match std::str::from_utf8(line).map(Args::parse) {
+			Ok(args) => args,
+			Err(_) => {
+				self.broken = true;
+				return;
+			}
+		};
+		if args.len() == 0 {
+			return;
+		}
+		let parsed = match (args.get(0).map(str::parse), args.get(1).map(str::parse), args.get(2), args.rest(3)) {
+			(Some(Ok(size)), Some(Ok(update_time)), Some(hash), Some(path)) => Some((size, update_time, hash, path)),
+			_ => None
+		};
+		let (size, update_time, hash, path) = match parsed {
+			Some(parsed) => parsed,
+			None => {
+				self.broken = true;
+				return;
+			}
+		};
+		if !self.seen.insert(path.to_string()) {
+			return;
+		}
+		let local = Record {
+			id: 0,
+			revision: 0,
+			update_time,
+			size,
+			hash: Some(hash).filter(|hash| *hash != "-").map(String::from)
+		};
+		match stash.record(&path) {
+			Some(record) if record.same_content(&local) => (),
+			Some(_) => self.changes.push(('~', path.into_owned())),
+			None => self.changes.push(('+', path.into_owned()))
+		}
+	}
+
+	/// Paths that are new (`+`), changed (`~`) or deleted (`-`) on the client compared to the stash,
+	/// sorted by path
+	pub fn finish(mut self) -> Result<Vec<(char, String)>, Response> {
+		// the last entry doesn't need a line break
+		if !self.partial.is_empty() && !self.broken {
+			let entry = std::mem::take(&mut self.partial);
+			self.entry(&entry);
+		}
+		if self.broken {
+			return Err(Response::BadFormat);
+		}
+		let stash = self.stash?;
+		let mut changes = self.changes;
+		changes.extend(
+			stash.names("").filter(|name| !self.seen.contains(*name)).map(|name| ('-', name.to_string()))
+		);
+		changes.sort_by(|a, b| a.1.cmp(&b.1));
+		Ok(changes)
+	}
+}
@@ -0,0 +1,205 @@
+use std::{
+	io::ErrorKind,
+	sync::atomic::{AtomicU64, Ordering}
+};
+use anyhow::Result;
+use async_std::sync::Arc;
+use sha3::{Sha3_256, Digest};
+use crate::{
+	config::Config,
+	info::{
+		stash::Stash,
+		file::hash_file,
+		format::Encoder,
+		staging::{Staged, staged_path}
+	},
+	warning
+};
+use super::Response;
+
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A file that is being received from a client
+pub struct Upload {
+	info: String,
+	stash_name: String,
+	name: String,
+	update_time: u64,
+	size: u64,
+	left: u64,
+	dest: Destination
+}
+
+enum Destination {
+	/// The data is read and thrown away, the response is sent after that
+	Discard(Response),
+	/// A one-shot upload, the file is removed if it's not finished
+	Temp {
+		stash: Arc<Stash>,
+		path: String,
+		encoder: Encoder,
+		hasher: Sha3_256
+	},
+	/// A resumable upload, the data stays in the staging area until it's complete
+	Staged {
+		stash: Arc<Stash>,
+		token: String,
+		encoder: Encoder,
+		complete: bool
+	}
+}
+
+pub enum Finish {
+	Stored,
+	Partial,
+	Rejected(Response)
+}
+
+impl Upload {
+	pub async fn new(stash_name: &str, stash: Option<Arc<Stash>>, name: &str, size: u64, update_time: u64) -> Result<Self> {
+		let dest = match stash {
+			Some(stash) => {
+				let path = format!(
+					"{}/.upload-{}-{}",
+					Config::get().storage_path,
+					std::process::id(),
+					UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+				);
+				let encoder = Encoder::create(&path, size).await?;
+				Destination::Temp { stash, path, encoder, hasher: Sha3_256::new() }
+			}
+			None => Destination::Discard(Response::NoStash)
+		};
+		Ok(Upload {
+			info: format!("{stash_name} {name}"),
+			stash_name: stash_name.into(),
+			name: name.into(),
+			update_time,
+			size,
+			left: size,
+			dest
+		})
+	}
+
+	/// Continues a staged upload, `len` bytes will be appended to already received data
+	pub async fn resume(token: &str, staged: Staged, stash: Arc<Stash>, received: u64, len: u64) -> Result<Self> {
+		let encoder = Encoder::append(&staged_path(token)).await?;
+		Ok(Upload {
+			info: format!("{} {}", staged.stash, staged.name),
+			stash_name: staged.stash,
+			name: staged.name,
+			update_time: staged.update_time,
+			size: staged.size,
+			left: len,
+			dest: Destination::Staged {
+				stash,
+				token: token.into(),
+				encoder,
+				complete: received + len == staged.size
+			}
+		})
+	}
+
+	pub fn discard(info: &str, len: u64, res: Response) -> Self {
+		Upload {
+			info: info.into(),
+			stash_name: String::new(),
+			name: String::new(),
+			update_time: 0,
+			size: len,
+			left: len,
+			dest: Destination::Discard(res)
+		}
+	}
+
+	pub fn stash_name(&self) -> &str {
+		&self.stash_name
+	}
+
+	/// Description of the upload for the audit log
+	pub fn info(&self) -> &str {
+		&self.info
+	}
+
+	pub fn token(&self) -> Option<&str> {
+		match self.dest {
+			Destination::Staged { ref token, .. } => Some(token),
+			_ => None
+		}
+	}
+
+	/// The stash and the size of a resumable upload that is complete once this part is written
+	pub fn completes(&self) -> Option<(&Stash, u64)> {
+		match self.dest {
+			Destination::Staged { ref stash, complete: true, .. } => Some((stash, self.size)),
+			_ => None
+		}
+	}
+
+	pub fn left(&self) -> u64 {
+		self.left
+	}
+
+	/// The data counts as received even if it can't be written, so the rest can still be skipped
+	pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+		self.left -= data.len() as u64;
+		match self.dest {
+			Destination::Temp { ref mut encoder, ref mut hasher, .. } => {
+				encoder.write(data).await?;
+				hasher.update(data);
+			}
+			Destination::Staged { ref mut encoder, .. } => encoder.write(data).await?,
+			Destination::Discard(_) => ()
+		}
+		Ok(())
+	}
+
+	/// Moves received data to the storage and updates the database once the whole file is here
+	pub async fn finish(mut self) -> Result<Finish> {
+		let dest = std::mem::replace(&mut self.dest, Destination::Discard(Response::None));
+		match dest {
+			Destination::Discard(res) => Ok(Finish::Rejected(res)),
+			Destination::Temp { stash, path, encoder, hasher } => {
+				let hash = format!("{:x}", hasher.finalize());
+				let res = async {
+					encoder.finish().await?;
+					self.store(&stash, &path, &hash).await
+				}.await;
+				if res.is_err() {
+					remove_tmp(&path);
+				}
+				res.map(|_| Finish::Stored)
+			}
+			Destination::Staged { stash, token, encoder, complete } => {
+				encoder.finish().await?;
+				if complete {
+					let path = staged_path(&token);
+					let hash = hash_file(&path).await?;
+					self.store(&stash, &path, &hash).await?;
+					Ok(Finish::Stored)
+				} else {
+					Ok(Finish::Partial)
+				}
+			}
+		}
+	}
+
+	async fn store(&self, stash: &Stash, path: &str, hash: &str) -> Result<()> {
+		stash.put_file(&self.name, self.update_time, self.size, hash, path).await
+	}
+}
+
+impl Drop for Upload {
+	fn drop(&mut self) {
+		if let Destination::Temp { ref path, .. } = self.dest {
+			remove_tmp(path);
+		}
+	}
+}
+
+fn remove_tmp(path: &str) {
+	match std::fs::remove_file(path) {
+		Err(err) if err.kind() != ErrorKind::NotFound => warning!("Can't remove temporary file <{path}>: {err}"),
+		_ => ()
+	}
+}
@@ -30,10 +30,15 @@ pub enum Event {
 	Auth,
 	NewStash,
 	DeleteStash,
+	RenameStash,
 	List,
 	Download,
 	Upload,
-	DeleteFile
+	DeleteFile,
+	MoveFile,
+	CopyFile,
+	NewSnapshot,
+	DeleteSnapshot
 }
 
 impl Into<&str> for Event {
@@ -43,10 +48,15 @@ impl Into<&str> for Event {
 			Auth => "AUTH",
 			NewStash => "NEW_STASH",
 			DeleteStash => "DELETE_STASH",
+			RenameStash => "RENAME_STASH",
 			List => "LIST",
 			Download => "DOWNLOAD",
 			Upload => "UPLOAD",
-			DeleteFile => "DELETE_FILE"
+			DeleteFile => "DELETE_FILE",
+			MoveFile => "MOVE_FILE",
+			CopyFile => "COPY_FILE",
+			NewSnapshot => "NEW_SNAPSHOT",
+			DeleteSnapshot => "DELETE_SNAPSHOT"
 		}
 	}
 }
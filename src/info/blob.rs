@@ -0,0 +1,130 @@
+use std::{collections::HashSet, io};
+use anyhow::Result;
+use async_std::{fs, sync::Mutex};
+use sqlx::{MySqlPool, query};
+use crate::{
+	config::Config,
+	debug, warning
+};
+use super::format;
+
+lazy_static::lazy_static! {
+	/// Keeps reference counting and files on disk consistent with each other
+	static ref STORE_LOCK: Mutex<()> = Mutex::new(());
+	static ref MIGRATING: std::sync::Mutex<HashSet<String>> = std::sync::Mutex::new(HashSet::new());
+}
+
+/// Adds a reference to a blob with the given content.
+/// The data at `path` has to be written by [`format::Encoder`]. It becomes a new blob
+/// if there is none with the same hash yet, otherwise it is removed.
+pub async fn acquire(db: &MySqlPool, path: &str, hash: &str, size: u64) -> Result<()> {
+	let _lock = STORE_LOCK.lock().await;
+	let res = store(db, path, hash, size).await;
+	if res.is_err() {
+		remove_if_exists(path).await;
+	}
+	res
+}
+
+async fn store(db: &MySqlPool, path: &str, hash: &str, size: u64) -> Result<()> {
+	let mut db = db.acquire().await?;
+	let query = query!("SELECT refs FROM content WHERE hash=?", hash);
+	match query.fetch_optional(&mut db).await? {
+		Some(_) => {
+			query!("UPDATE content SET refs=refs+1 WHERE hash=?", hash).execute(&mut db).await?;
+			fs::remove_file(path).await?;
+		}
+		None => {
+			fs::create_dir_all(blob_dir()).await?;
+			fs::rename(path, blob_path(hash)).await?;
+			query!(
+				"INSERT INTO content (hash, size, refs) VALUES (?, ?, 1)",
+				hash,
+				size
+			).execute(&mut db).await?;
+		}
+	}
+	Ok(())
+}
+
+/// Re-encodes stored data that was kept as is, used once compression is enabled
+pub async fn migrate(path: String) {
+	if !MIGRATING.lock().unwrap().insert(path.clone()) {
+		return;
+	}
+	if let Err(err) = try_migrate(&path).await {
+		warning!("Can't migrate stored data <{path}>: {err}");
+	}
+	MIGRATING.lock().unwrap().remove(&path);
+}
+
+async fn try_migrate(path: &str) -> Result<()> {
+	let encoded = format!("{path}.enc");
+	if let Err(err) = format::encode(path, &encoded).await {
+		remove_if_exists(&encoded).await;
+		return Err(err.into());
+	}
+	let _lock = STORE_LOCK.lock().await;
+	// the data could have been removed while it was encoded
+	if fs::metadata(path).await.is_ok() {
+		fs::rename(&encoded, path).await?;
+		debug!("Migrated stored data <{path}>");
+	} else {
+		remove_if_exists(&encoded).await;
+	}
+	Ok(())
+}
+
+async fn remove_if_exists(path: &str) {
+	match fs::remove_file(path).await {
+		Err(err) if err.kind() != io::ErrorKind::NotFound => warning!("Can't remove <{path}>: {err}"),
+		_ => ()
+	}
+}
+
+/// Adds a reference to an existing blob.
+/// Returns `false` if there is no such blob.
+pub async fn add_ref(db: &MySqlPool, hash: &str) -> Result<bool> {
+	let _lock = STORE_LOCK.lock().await;
+	let mut db = db.acquire().await?;
+	let res = query!("UPDATE content SET refs=refs+1 WHERE hash=?", hash).execute(&mut db).await?;
+	Ok(res.rows_affected() > 0)
+}
+
+/// Adds a reference for every hash in the list.
+/// Returns hashes of blobs that don't exist.
+pub async fn add_refs(db: &MySqlPool, hashes: &[String]) -> Result<Vec<String>> {
+	let _lock = STORE_LOCK.lock().await;
+	let mut db = db.acquire().await?;
+	let mut missing = vec![];
+	for hash in hashes {
+		let res = query!("UPDATE content SET refs=refs+1 WHERE hash=?", hash).execute(&mut db).await?;
+		if res.rows_affected() == 0 {
+			missing.push(hash.clone());
+		}
+	}
+	Ok(missing)
+}
+
+/// Removes a reference to a blob, the blob is deleted once nothing uses it
+pub async fn release(db: &MySqlPool, hash: &str) -> Result<()> {
+	let _lock = STORE_LOCK.lock().await;
+	let mut db = db.acquire().await?;
+	query!("UPDATE content SET refs=refs-1 WHERE hash=? AND refs>0", hash).execute(&mut db).await?;
+	let query = query!("SELECT refs FROM content WHERE hash=?", hash);
+	if let Some(res) = query.fetch_optional(&mut db).await? {
+		if res.refs == 0 {
+			query!("DELETE FROM content WHERE hash=?", hash).execute(&mut db).await?;
+			remove_if_exists(&blob_path(hash)).await;
+		}
+	}
+	Ok(())
+}
+
+pub fn blob_path(hash: &str) -> String {
+	format!("{}/{hash}", blob_dir())
+}
+
+fn blob_dir() -> String {
+	format!("{}/blob", Config::get().storage_path)
+}
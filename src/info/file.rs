@@ -1,21 +1,59 @@
+use std::io::{self, ErrorKind, SeekFrom};
+use anyhow::Result;
+use async_std::{
+	fs,
+	io::{ReadExt, SeekExt}
+};
+use sha3::{Sha3_256, Digest};
+use sqlx::MySqlPool;
 use crate::{
 	config::Config,
 	warning
 };
+use super::{blob, format, keys};
+
+/// A row of the `file` table
+#[derive(Clone)]
+pub struct Record {
+	pub id: u64,
+	pub revision: u64,
+	pub update_time: u64,
+	pub size: u64,
+	/// Files stored before hashes were recorded don't have one
+	/// and keep their data outside of the blob store
+	pub hash: Option<String>
+}
+
+impl Record {
+	pub fn path(&self) -> String {
+		match self.hash {
+			Some(ref hash) => blob::blob_path(hash),
+			None => legacy_path(self.id)
+		}
+	}
+
+	/// Compares hashes if both records have them, otherwise falls back to size and update time
+	pub fn same_content(&self, other: &Record) -> bool {
+		match (&self.hash, &other.hash) {
+			(Some(hash), Some(other_hash)) => hash == other_hash,
+			_ => self.size == other.size && self.update_time == other.update_time
+		}
+	}
+}
 
 pub struct File {
-	id: u64,
-	update_time: u64,
+	record: Record,
 	path: String
 }
 
 impl File {
-	pub fn new(id: u64, update_time: u64) -> Option<Self> {
-		let path = format!("{}/{id}", Config::get().storage_path);
+	pub fn new(record: &Record) -> Option<Self> {
+		let path = record.path();
 		match std::fs::metadata(&path) {
 			Ok(meta) => if meta.is_file() {
 				Some(File {
-					id, update_time, path,
+					record: record.clone(),
+					path
 				})
 			} else {
 				warning!("File <{path}> doesn't seem to be a file");
@@ -29,14 +67,198 @@ impl File {
 	}
 
 	pub fn id(&self) -> u64 {
-		self.id
+		self.record.id
 	}
 
 	pub fn update_time(&self) -> u64 {
-		self.update_time
+		self.record.update_time
+	}
+
+	pub fn size(&self) -> u64 {
+		self.record.size
+	}
+
+	pub fn hash(&self) -> Option<&str> {
+		self.record.hash.as_deref()
+	}
+
+	pub async fn open(&self) -> io::Result<Reader> {
+		Ok(self.open_range(0, None).await?.expect("zero offset is always in range"))
 	}
 
-	pub fn read(&self) -> Vec<u8> {
-		std::fs::read(&self.path).unwrap()
+	/// Opens a part of the file starting at `offset`.
+	/// Length is cut at the end of the file, `None` means everything after `offset`.
+	/// Returns `None` if `offset` is past the end of the file.
+	pub async fn open_range(&self, offset: u64, len: Option<u64>) -> io::Result<Option<Reader>> {
+		Reader::open(&self.path, offset, len).await
 	}
 }
+
+/// Reads contents of a stored file in chunks
+pub struct Reader {
+	file: fs::File,
+	/// `None` if the data is stored as is
+	header: Option<format::Header>,
+	len: u64,
+	left: u64,
+	/// Decoded data of the current chunk
+	chunk: Vec<u8>,
+	chunk_pos: usize,
+	/// Index of the next chunk in the file
+	chunk_index: u32
+}
+
+impl Reader {
+	/// Same as [`File::open_range`] but for any stored data
+	pub async fn open(path: &str, offset: u64, len: Option<u64>) -> io::Result<Option<Self>> {
+		let mut file = fs::File::open(path).await?;
+		let header = format::read_header(&mut file).await?;
+		let size = match header {
+			Some(ref header) => header.size,
+			None => {
+				if Config::get().compression_level > 0 || keys::current().is_some() {
+					async_std::task::spawn(blob::migrate(path.to_string()));
+				}
+				file.metadata().await?.len()
+			}
+		};
+		if offset > size {
+			return Ok(None);
+		}
+		let len = match len {
+			Some(len) if len < size - offset => len,
+			_ => size - offset
+		};
+		let mut reader = Reader {
+			file,
+			header,
+			len,
+			left: len,
+			chunk: vec![],
+			chunk_pos: 0,
+			chunk_index: 0
+		};
+		reader.skip(offset).await?;
+		Ok(Some(reader))
+	}
+
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+
+	/// Bytes that haven't been read yet
+	pub fn left(&self) -> u64 {
+		self.left
+	}
+
+	async fn skip(&mut self, offset: u64) -> io::Result<()> {
+		let header = match self.header {
+			Some(ref header) => header,
+			None => {
+				self.file.seek(SeekFrom::Start(offset)).await?;
+				return Ok(());
+			}
+		};
+		let mut skip = offset;
+		while skip > 0 {
+			match format::read_chunk_header(&mut self.file).await? {
+				Some(chunk) if chunk.raw_len as u64 <= skip => {
+					format::skip_chunk(&mut self.file, &chunk).await?;
+					skip -= chunk.raw_len as u64;
+					self.chunk_index += 1;
+				}
+				Some(chunk) => {
+					self.chunk = format::read_chunk(&mut self.file, header, &chunk, self.chunk_index).await?;
+					self.chunk_pos = skip as usize;
+					self.chunk_index += 1;
+					break;
+				}
+				None => break
+			}
+		}
+		Ok(())
+	}
+
+	/// Returns 0 once all of the data is read.
+	/// It's an error for the file to end before the expected length.
+	pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.left == 0 {
+			return Ok(0);
+		}
+		let max = if (buf.len() as u64) < self.left {
+			buf.len()
+		} else {
+			self.left as usize
+		};
+		let len = match self.header {
+			Some(ref header) => {
+				if self.chunk_pos == self.chunk.len() {
+					match format::read_chunk_header(&mut self.file).await? {
+						Some(chunk) => {
+							self.chunk = format::read_chunk(&mut self.file, header, &chunk, self.chunk_index).await?;
+							self.chunk_pos = 0;
+							self.chunk_index += 1;
+						}
+						None => {
+							self.chunk.clear();
+							self.chunk_pos = 0;
+						}
+					}
+				}
+				let len = max.min(self.chunk.len() - self.chunk_pos);
+				buf[..len].copy_from_slice(&self.chunk[self.chunk_pos..self.chunk_pos + len]);
+				self.chunk_pos += len;
+				len
+			}
+			None => self.file.read(&mut buf[..max]).await?
+		};
+		if len == 0 {
+			return Err(io::Error::new(ErrorKind::UnexpectedEof, "stored file is shorter than expected"));
+		}
+		self.left -= len as u64;
+		Ok(len)
+	}
+}
+
+/// SHA3-256 of the original data of a stored file in hex
+pub async fn hash_file(path: &str) -> io::Result<String> {
+	let mut reader = Reader::open(path, 0, None).await?.expect("zero offset is always in range");
+	let mut hasher = Sha3_256::new();
+	let mut buf = vec![0; 65536];
+	loop {
+		let len = reader.read(&mut buf).await?;
+		if len == 0 {
+			break;
+		}
+		hasher.update(&buf[..len]);
+	}
+	Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Drops the data of a file whose record was removed
+pub async fn release(db: &MySqlPool, id: u64, hash: Option<&str>) -> Result<()> {
+	match hash {
+		Some(hash) => blob::release(db, hash).await,
+		None => {
+			let path = legacy_path(id);
+			if let Err(err) = fs::remove_file(&path).await {
+				warning!("Can't remove stored file <{path}>: {err}");
+			}
+			Ok(())
+		}
+	}
+}
+
+/// Size of the original data, whether it's encoded or kept as is
+pub async fn stored_size(path: &str) -> io::Result<u64> {
+	let mut file = fs::File::open(path).await?;
+	match format::read_header(&mut file).await? {
+		Some(header) => Ok(header.size),
+		None => Ok(file.metadata().await?.len())
+	}
+}
+
+/// Where data was kept before the blob store
+fn legacy_path(id: u64) -> String {
+	format!("{}/{id}", Config::get().storage_path)
+}
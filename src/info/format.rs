@@ -0,0 +1,494 @@
+//! Layout of stored data on disk.
+//!
+//! Data is either kept as is or, if it starts with [`MAGIC`], as a header
+//! followed by chunks. Every chunk has its own header with lengths and flags
+//! so a part of a file can be read without decoding everything before it.
+//! Encrypted chunks are sealed with AES-256-GCM, the nonce is a random
+//! per-blob prefix followed by the chunk index.
+//!
+//! Uploads are encoded as they are received, so data on disk is never
+//! kept in plain text while encryption is enabled.
+//!
+//! Encryption protects contents only. Blobs are still named by the SHA3-256 of
+//! their original data, so anyone who can list the storage can check whether
+//! a file they already have is stored there.
+
+use std::io::{self, ErrorKind, SeekFrom};
+use async_std::{
+	fs,
+	io::{ReadExt, WriteExt, SeekExt}
+};
+use openssl::symm::{Cipher, encrypt_aead, decrypt_aead};
+use crate::config::Config;
+use super::keys;
+
+const MAGIC: &[u8; 8] = b"\x89ABKBLB\n";
+const VERSION: u8 = 1;
+/// Magic, version, flags and size of the original data
+const HEADER_LEN: usize = 8 + 1 + 1 + 8;
+/// Original length, stored length and flags
+const CHUNK_HEADER_LEN: usize = 4 + 4 + 1;
+const CHUNK_SIZE: usize = 65536;
+const NONCE_PREFIX_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+
+/// Followed by key ID length, key ID and nonce prefix
+const ENCRYPTED: u8 = 1;
+const CHUNK_COMPRESSED: u8 = 1;
+
+pub struct Header {
+	/// Size of the original data
+	pub size: u64,
+	pub key_id: Option<String>,
+	cipher: Option<([u8; 32], [u8; NONCE_PREFIX_LEN])>,
+	/// Authenticated along with every chunk
+	bytes: Vec<u8>
+}
+
+pub struct ChunkHeader {
+	pub raw_len: u32,
+	stored_len: u32,
+	flags: u8,
+	bytes: [u8; CHUNK_HEADER_LEN]
+}
+
+/// Returns `None` for data stored as is.
+/// The file is positioned at the start of the data either way.
+pub async fn read_header(file: &mut fs::File) -> io::Result<Option<Header>> {
+	let mut buf = [0; HEADER_LEN];
+	let len = read_full(file, &mut buf).await?;
+	if len < HEADER_LEN || &buf[..8] != MAGIC {
+		file.seek(SeekFrom::Start(0)).await?;
+		return Ok(None);
+	}
+	if buf[8] != VERSION {
+		return Err(invalid(format!("unknown stored data version {}", buf[8])));
+	}
+	let mut header = Header {
+		size: u64::from_be_bytes(buf[10..18].try_into().unwrap()),
+		key_id: None,
+		cipher: None,
+		bytes: buf.to_vec()
+	};
+	if buf[9] & ENCRYPTED != 0 {
+		let mut id_len = [0];
+		file.read_exact(&mut id_len).await?;
+		let mut id = vec![0; id_len[0] as usize];
+		file.read_exact(&mut id).await?;
+		let mut nonce = [0; NONCE_PREFIX_LEN];
+		file.read_exact(&mut nonce).await?;
+		header.bytes.extend(id_len);
+		header.bytes.extend(&id);
+		header.bytes.extend(nonce);
+
+		let id = String::from_utf8(id).map_err(|_| invalid("bad key ID".into()))?;
+		let key = keys::get(&id).ok_or_else(|| invalid(format!("unknown key \"{id}\"")))?;
+		header.key_id = Some(id);
+		header.cipher = Some((key, nonce));
+	}
+	Ok(Some(header))
+}
+
+/// Returns `None` at the end of the file
+pub async fn read_chunk_header(file: &mut fs::File) -> io::Result<Option<ChunkHeader>> {
+	let mut buf = [0; CHUNK_HEADER_LEN];
+	match read_full(file, &mut buf).await? {
+		0 => Ok(None),
+		CHUNK_HEADER_LEN => Ok(Some(ChunkHeader {
+			raw_len: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+			stored_len: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+			flags: buf[8],
+			bytes: buf
+		})),
+		_ => Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated chunk header"))
+	}
+}
+
+pub async fn skip_chunk(file: &mut fs::File, chunk: &ChunkHeader) -> io::Result<()> {
+	file.seek(SeekFrom::Current(chunk.stored_len as i64)).await?;
+	Ok(())
+}
+
+/// Reads and decodes data of the chunk with the given index
+pub async fn read_chunk(file: &mut fs::File, header: &Header, chunk: &ChunkHeader, index: u32) -> io::Result<Vec<u8>> {
+	let mut stored = vec![0; chunk.stored_len as usize];
+	file.read_exact(&mut stored).await?;
+	if let Some((ref key, ref prefix)) = header.cipher {
+		if stored.len() < TAG_LEN {
+			return Err(invalid("encrypted chunk is too short".into()));
+		}
+		let (data, tag) = stored.split_at(stored.len() - TAG_LEN);
+		stored = decrypt_aead(
+			Cipher::aes_256_gcm(),
+			key,
+			Some(&nonce(prefix, index)),
+			&aad(header, chunk),
+			data,
+			tag
+		).map_err(|_| invalid("stored data failed authentication".into()))?;
+	}
+	let raw = if chunk.flags & CHUNK_COMPRESSED != 0 {
+		zstd::bulk::decompress(&stored, chunk.raw_len as usize)?
+	} else {
+		stored
+	};
+	if raw.len() != chunk.raw_len as usize {
+		return Err(invalid("chunk length mismatch".into()));
+	}
+	Ok(raw)
+}
+
+/// Data has to be encoded if compression or encryption is enabled
+/// or if it would be mistaken for encoded data
+pub async fn needs_encoding(path: &str) -> io::Result<bool> {
+	if Config::get().compression_level > 0 || keys::current().is_some() {
+		return Ok(true);
+	}
+	let mut file = fs::File::open(path).await?;
+	let mut buf = [0; MAGIC.len()];
+	Ok(read_full(&mut file, &mut buf).await? == MAGIC.len() && &buf == MAGIC)
+}
+
+/// Length of the original data in all chunks of encoded data that is still being written
+pub async fn written_len(path: &str) -> io::Result<u64> {
+	let mut file = fs::File::open(path).await?;
+	Ok(scan(&mut file).await?.2)
+}
+
+/// Reads the header and skips all chunks after it.
+/// Returns the header, the number of chunks and the length of their original data.
+/// A chunk cut short is an error, writing it again would reuse its nonce.
+async fn scan(file: &mut fs::File) -> io::Result<(Header, u32, u64)> {
+	let header = read_header(file).await?.ok_or_else(|| invalid("data isn't encoded".into()))?;
+	let end = file.metadata().await?.len();
+	let mut count = 0;
+	let mut len = 0;
+	while let Some(chunk) = read_chunk_header(file).await? {
+		if file.seek(SeekFrom::Current(chunk.stored_len as i64)).await? > end {
+			return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated chunk"));
+		}
+		count += 1;
+		len += chunk.raw_len as u64;
+	}
+	Ok((header, count, len))
+}
+
+/// Writes data from `src` to `dst` in the chunked format
+pub async fn encode(src: &str, dst: &str) -> io::Result<()> {
+	let mut input = fs::File::open(src).await?;
+	let size = input.metadata().await?.len();
+	let mut encoder = Encoder::create(dst, size).await?;
+	let mut buf = vec![0; CHUNK_SIZE];
+	loop {
+		let len = input.read(&mut buf).await?;
+		if len == 0 {
+			break;
+		}
+		encoder.write(&buf[..len]).await?;
+	}
+	encoder.finish().await
+}
+
+/// Writes data in the chunked format with current compression and encryption settings
+pub struct Encoder {
+	output: fs::File,
+	header: Header,
+	level: i32,
+	buf: Vec<u8>,
+	index: u32
+}
+
+impl Encoder {
+	/// `size` is the total length of data that is going to be written
+	pub async fn create(path: &str, size: u64) -> io::Result<Self> {
+		Self::with_settings(path, size, Config::get().compression_level, keys::current()).await
+	}
+
+	/// Same as [`Encoder::create`] with the given zstd level and key
+	pub async fn with_settings(path: &str, size: u64, level: i32, key: Option<keys::Key>) -> io::Result<Self> {
+		let mut header = Header {
+			size,
+			key_id: None,
+			cipher: None,
+			bytes: Vec::with_capacity(HEADER_LEN)
+		};
+		header.bytes.extend(MAGIC);
+		header.bytes.push(VERSION);
+		match key {
+			Some(key) => {
+				let mut prefix = [0; NONCE_PREFIX_LEN];
+				openssl::rand::rand_bytes(&mut prefix)?;
+				header.bytes.push(ENCRYPTED);
+				header.bytes.extend(size.to_be_bytes());
+				header.bytes.push(key.id.len() as u8);
+				header.bytes.extend(key.id.as_bytes());
+				header.bytes.extend(prefix);
+				header.cipher = Some((key.key, prefix));
+				header.key_id = Some(key.id);
+			}
+			None => {
+				header.bytes.push(0);
+				header.bytes.extend(size.to_be_bytes());
+			}
+		}
+		let mut output = fs::File::create(path).await?;
+		output.write_all(&header.bytes).await?;
+		Ok(Encoder {
+			output,
+			header,
+			level,
+			buf: Vec::with_capacity(CHUNK_SIZE),
+			index: 0
+		})
+	}
+
+	/// Continues data of an encoder that was finished before all of it was written.
+	/// The key stays the same, compression follows current settings.
+	pub async fn append(path: &str) -> io::Result<Self> {
+		Self::append_with_level(path, Config::get().compression_level).await
+	}
+
+	/// Same as [`Encoder::append`] with the given zstd level
+	pub async fn append_with_level(path: &str, level: i32) -> io::Result<Self> {
+		let mut output = fs::OpenOptions::new().read(true).write(true).open(path).await?;
+		let (header, index, _) = scan(&mut output).await?;
+		Ok(Encoder {
+			output,
+			header,
+			level,
+			buf: Vec::with_capacity(CHUNK_SIZE),
+			index
+		})
+	}
+
+	pub async fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+		while !data.is_empty() {
+			let len = data.len().min(CHUNK_SIZE - self.buf.len());
+			self.buf.extend(&data[..len]);
+			data = &data[len..];
+			if self.buf.len() == CHUNK_SIZE {
+				self.write_chunk().await?;
+			}
+		}
+		Ok(())
+	}
+
+	pub async fn finish(mut self) -> io::Result<()> {
+		if !self.buf.is_empty() {
+			self.write_chunk().await?;
+		}
+		self.output.sync_all().await
+	}
+
+	async fn write_chunk(&mut self) -> io::Result<()> {
+		let raw = &self.buf[..];
+		let compressed = if self.level > 0 {
+			Some(zstd::bulk::compress(raw, self.level)?).filter(|data| data.len() < raw.len())
+		} else {
+			None
+		};
+		let (flags, data) = match compressed {
+			Some(ref data) => (CHUNK_COMPRESSED, &data[..]),
+			None => (0, raw)
+		};
+		let stored_len = data.len() + if self.header.cipher.is_some() { TAG_LEN } else { 0 };
+		let mut bytes = [0; CHUNK_HEADER_LEN];
+		bytes[0..4].copy_from_slice(&(raw.len() as u32).to_be_bytes());
+		bytes[4..8].copy_from_slice(&(stored_len as u32).to_be_bytes());
+		bytes[8] = flags;
+		let chunk = ChunkHeader {
+			raw_len: raw.len() as u32,
+			stored_len: stored_len as u32,
+			flags,
+			bytes
+		};
+		self.output.write_all(&chunk.bytes).await?;
+		match self.header.cipher {
+			Some((ref key, ref prefix)) => {
+				let mut tag = [0; TAG_LEN];
+				let encrypted = encrypt_aead(
+					Cipher::aes_256_gcm(),
+					key,
+					Some(&nonce(prefix, self.index)),
+					&aad(&self.header, &chunk),
+					data,
+					&mut tag
+				)?;
+				self.output.write_all(&encrypted).await?;
+				self.output.write_all(&tag).await?;
+			}
+			None => self.output.write_all(data).await?
+		}
+		self.buf.clear();
+		self.index += 1;
+		Ok(())
+	}
+}
+
+fn nonce(prefix: &[u8; NONCE_PREFIX_LEN], index: u32) -> [u8; 12] {
+	let mut res = [0; 12];
+	res[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+	res[NONCE_PREFIX_LEN..].copy_from_slice(&index.to_be_bytes());
+	res
+}
+
+/// Both headers are authenticated so they can't be swapped or altered
+fn aad(header: &Header, chunk: &ChunkHeader) -> Vec<u8> {
+	let mut res = header.bytes.clone();
+	res.extend(chunk.bytes);
+	res
+}
+
+fn invalid(msg: String) -> io::Error {
+	io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Reads until the buffer is full or the file ends
+async fn read_full(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+	let mut pos = 0;
+	while pos < buf.len() {
+		let len = file.read(&mut buf[pos..]).await?;
+		if len == 0 {
+			break;
+		}
+		pos += len;
+	}
+	Ok(pos)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::info::file::Reader;
+	use super::*;
+
+	const KEY_ID: &str = "test";
+
+	fn key() -> keys::Key {
+		let path = temp_path("keys");
+		std::fs::write(&path, format!("{KEY_ID} {}\n", "42".repeat(32))).unwrap();
+		keys::load(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		keys::Key { id: KEY_ID.into(), key: keys::get(KEY_ID).unwrap() }
+	}
+
+	fn temp_path(name: &str) -> String {
+		format!("{}/format-test-{}-{name}", std::env::temp_dir().display(), std::process::id())
+	}
+
+	/// Compressible but not uniform, a bit more than three chunks
+	fn sample() -> Vec<u8> {
+		(0..CHUNK_SIZE * 3 + 1000).map(|pos| (pos / 7 % 251) as u8).collect()
+	}
+
+	async fn encode_with(path: &str, data: &[u8], level: i32, key: Option<keys::Key>) {
+		let mut encoder = Encoder::with_settings(path, data.len() as u64, level, key).await.unwrap();
+		// uneven writes cross chunk boundaries
+		for piece in data.chunks(10000) {
+			encoder.write(piece).await.unwrap();
+		}
+		encoder.finish().await.unwrap();
+	}
+
+	async fn read_all(path: &str, offset: u64, len: Option<u64>) -> io::Result<Vec<u8>> {
+		let mut reader = Reader::open(path, offset, len).await?.unwrap();
+		let mut res = vec![];
+		let mut buf = vec![0; 5000];
+		loop {
+			let len = reader.read(&mut buf).await?;
+			if len == 0 {
+				return Ok(res);
+			}
+			res.extend_from_slice(&buf[..len]);
+		}
+	}
+
+	async fn round_trip(name: &str, level: i32, key: Option<keys::Key>) {
+		let path = temp_path(name);
+		let data = sample();
+		encode_with(&path, &data, level, key).await;
+		assert_eq!(read_all(&path, 0, None).await.unwrap(), data);
+		let (offset, len) = (CHUNK_SIZE as u64 + 123, 2 * CHUNK_SIZE as u64);
+		assert_eq!(
+			read_all(&path, offset, Some(len)).await.unwrap(),
+			&data[offset as usize..(offset + len) as usize]
+		);
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[async_std::test]
+	async fn plain_round_trip() {
+		round_trip("plain", 0, None).await;
+	}
+
+	#[async_std::test]
+	async fn compressed_round_trip() {
+		round_trip("compressed", 3, None).await;
+		let path = temp_path("compressed-size");
+		encode_with(&path, &sample(), 3, None).await;
+		assert!(std::fs::metadata(&path).unwrap().len() < sample().len() as u64);
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[async_std::test]
+	async fn encrypted_round_trip() {
+		round_trip("encrypted", 0, Some(key())).await;
+		round_trip("encrypted-compressed", 3, Some(key())).await;
+	}
+
+	#[async_std::test]
+	async fn appended_round_trip() {
+		let path = temp_path("appended");
+		let data = sample();
+		let mut encoder = Encoder::with_settings(&path, data.len() as u64, 0, Some(key())).await.unwrap();
+		encoder.write(&data[..10000]).await.unwrap();
+		encoder.finish().await.unwrap();
+		assert_eq!(written_len(&path).await.unwrap(), 10000);
+		let mut encoder = Encoder::append_with_level(&path, 3).await.unwrap();
+		encoder.write(&data[10000..]).await.unwrap();
+		encoder.finish().await.unwrap();
+		assert_eq!(written_len(&path).await.unwrap(), data.len() as u64);
+		assert_eq!(read_all(&path, 0, None).await.unwrap(), data);
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[async_std::test]
+	async fn truncated_chunk_is_not_appended() {
+		let path = temp_path("truncated");
+		encode_with(&path, &sample(), 0, Some(key())).await;
+		let len = std::fs::metadata(&path).unwrap().len();
+		std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 10).unwrap();
+		assert_eq!(written_len(&path).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+		assert!(Encoder::append_with_level(&path, 0).await.is_err());
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[async_std::test]
+	async fn tampered_chunk_is_rejected() {
+		let path = temp_path("tampered");
+		encode_with(&path, &sample(), 0, Some(key())).await;
+		let mut stored = std::fs::read(&path).unwrap();
+		let last = stored.len() - 1;
+		stored[last] ^= 1;
+		std::fs::write(&path, &stored).unwrap();
+		let err = read_all(&path, 0, None).await.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::InvalidData);
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[async_std::test]
+	async fn reordered_chunks_are_rejected() {
+		let path = temp_path("reordered");
+		encode_with(&path, &sample(), 0, Some(key())).await;
+		let mut stored = std::fs::read(&path).unwrap();
+		// full chunks are stored uncompressed, so they all have the same length
+		let chunk_len = CHUNK_HEADER_LEN + CHUNK_SIZE + TAG_LEN;
+		let header_len = HEADER_LEN + 1 + KEY_ID.len() + NONCE_PREFIX_LEN;
+		let first = stored[header_len..header_len + chunk_len].to_vec();
+		stored.copy_within(header_len + chunk_len..header_len + 2 * chunk_len, header_len);
+		stored[header_len + chunk_len..header_len + 2 * chunk_len].copy_from_slice(&first);
+		std::fs::write(&path, &stored).unwrap();
+		let err = read_all(&path, 0, None).await.unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::InvalidData);
+		std::fs::remove_file(&path).unwrap();
+	}
+}
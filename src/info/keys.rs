@@ -0,0 +1,99 @@
+use std::{
+	fmt::{self, Display},
+	sync::RwLock
+};
+use anyhow::Result;
+
+lazy_static::lazy_static! {
+	static ref KEYS: RwLock<Keys> = RwLock::new(Keys { list: vec![], encrypt: false });
+}
+
+struct Keys {
+	list: Vec<Key>,
+	/// New data is encrypted with the first key
+	encrypt: bool
+}
+
+/// A master key for stored data
+#[derive(Clone)]
+pub struct Key {
+	pub id: String,
+	pub key: [u8; 32]
+}
+
+/// Loads keys from a file with `<id> <key in hex>` lines.
+/// The first key is used for new data, the rest are only used to read older data.
+/// A `-` line before all keys turns encryption of new data off while the keys can still be read,
+/// rekeying with such a file decrypts everything.
+pub fn load(path: &str) -> Result<()> {
+	let content = std::fs::read_to_string(path)?;
+	let mut keys = vec![];
+	let mut encrypt = true;
+	for line in content.lines() {
+		let line = match line.split_once('#') {
+			Some((line, _)) => line,
+			None => line
+		}.trim();
+		if line.is_empty() {
+			continue;
+		}
+		if line == "-" && keys.is_empty() && encrypt {
+			encrypt = false;
+			continue;
+		}
+		let (id, hex) = match line.split_once(' ') {
+			Some((id, hex)) => (id.trim(), hex.trim()),
+			None => return Err(Error::MalformedLine(line.into()).into())
+		};
+		if id.len() > 255 || keys.iter().any(|key: &Key| key.id == id) {
+			return Err(Error::InvalidId(id.into()).into());
+		}
+		keys.push(Key {
+			id: id.into(),
+			key: parse_hex(hex).ok_or_else(|| Error::InvalidKey(id.into()))?
+		});
+	}
+	*KEYS.write().unwrap() = Keys { list: keys, encrypt };
+	Ok(())
+}
+
+/// The key for new data, `None` if encryption is disabled
+pub fn current() -> Option<Key> {
+	let keys = KEYS.read().unwrap();
+	keys.list.first().filter(|_| keys.encrypt).cloned()
+}
+
+pub fn get(id: &str) -> Option<[u8; 32]> {
+	KEYS.read().unwrap().list.iter().find(|key| key.id == id).map(|key| key.key)
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+	if hex.len() != 64 || !hex.is_ascii() {
+		return None;
+	}
+	let mut res = [0; 32];
+	for (pos, byte) in res.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&hex[pos * 2..pos * 2 + 2], 16).ok()?;
+	}
+	Some(res)
+}
+
+#[derive(Debug)]
+pub enum Error {
+	MalformedLine(String),
+	InvalidId(String),
+	InvalidKey(String)
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		use Error::*;
+		match self {
+			MalformedLine(line) => write!(f, "bad line in key file: {line}"),
+			InvalidId(id) => write!(f, "key id \"{id}\" is too long or repeated"),
+			InvalidKey(id) => write!(f, "key \"{id}\" is not 32 bytes in hex")
+		}
+	}
+}
+
+impl std::error::Error for Error {}
@@ -2,3 +2,8 @@ pub mod user;
 pub mod audit;
 pub mod stash;
 pub mod file;
+pub mod blob;
+pub mod format;
+pub mod keys;
+pub mod rekey;
+pub mod staging;
@@ -0,0 +1,89 @@
+use anyhow::Result;
+use async_std::{fs, prelude::StreamExt};
+use crate::{
+	config::Config,
+	error, info
+};
+use super::{
+	file::Reader,
+	format::{self, Encoder},
+	keys
+};
+
+/// Re-encodes all stored data that isn't sealed with the current key.
+/// With encryption turned off in the key file this decrypts everything.
+/// Staged uploads are left alone, their key has to stay in the key file until they finish or expire.
+pub async fn run() -> Result<()> {
+	let storage = Config::get().storage_path;
+	let mut paths = vec![];
+	paths.extend(list(&format!("{storage}/blob"), |_| true).await?);
+	// data stored before the blob store is named after file IDs
+	paths.extend(list(&storage, |name| name.parse::<u64>().is_ok()).await?);
+
+	let mut done = 0;
+	let mut failed = 0;
+	for path in &paths {
+		match rekey(path).await {
+			Ok(true) => done += 1,
+			Ok(false) => (),
+			Err(err) => {
+				error!("Can't rekey <{path}>: {err}");
+				failed += 1;
+			}
+		}
+	}
+	info!("Rekeyed {done} of {} stored files, {failed} failed", paths.len());
+	Ok(())
+}
+
+async fn list(dir: &str, filter: impl Fn(&str) -> bool) -> Result<Vec<String>> {
+	let mut res = vec![];
+	let mut entries = fs::read_dir(dir).await?;
+	while let Some(entry) = entries.next().await {
+		let entry = entry?;
+		if let Ok(name) = entry.file_name().into_string() {
+			if !name.ends_with(".enc") && !name.ends_with(".rekey") && filter(&name) && entry.metadata().await?.is_file() {
+				res.push(format!("{dir}/{name}"));
+			}
+		}
+	}
+	Ok(res)
+}
+
+/// Returns `false` if the data is already up to date
+async fn rekey(path: &str) -> Result<bool> {
+	let current = keys::current().map(|key| key.id);
+	let mut file = fs::File::open(path).await?;
+	let header = format::read_header(&mut file).await?;
+	drop(file);
+	// not the name used by lazy migration, so the two never write the same file
+	let encoded = format!("{path}.rekey");
+	match header {
+		Some(header) if header.key_id != current => (),
+		Some(_) => return Ok(false),
+		// data kept as is is encoded directly, opening a reader would start a migration of it
+		None if format::needs_encoding(path).await? => {
+			if let Err(err) = format::encode(path, &encoded).await {
+				let _ = fs::remove_file(&encoded).await;
+				return Err(err.into());
+			}
+			fs::rename(&encoded, path).await?;
+			return Ok(true);
+		}
+		None => return Ok(false)
+	}
+
+	let mut reader = Reader::open(path, 0, None).await?.expect("zero offset is always in range");
+	let mut encoder = Encoder::create(&encoded, reader.len()).await?;
+	let mut buf = vec![0; 65536];
+	loop {
+		let len = reader.read(&mut buf).await?;
+		if len == 0 {
+			break;
+		}
+		encoder.write(&buf[..len]).await?;
+	}
+	encoder.finish().await?;
+	fs::rename(&encoded, path).await?;
+	Ok(true)
+}
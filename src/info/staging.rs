@@ -0,0 +1,120 @@
+use std::{
+	io,
+	time::{Duration, SystemTime}
+};
+use anyhow::Result;
+use async_std::{fs, prelude::StreamExt};
+use sqlx::{MySqlPool, query};
+use crate::{
+	config::Config,
+	debug, warning
+};
+use super::{
+	format::{self, Encoder},
+	stash::Stash
+};
+
+/// Partially received uploads that can be continued later
+pub struct Staging(MySqlPool);
+
+pub struct Staged {
+	pub stash: String,
+	pub name: String,
+	pub size: u64,
+	pub update_time: u64
+}
+
+impl Staging {
+	pub fn new(db: &MySqlPool) -> Self {
+		Staging(db.clone())
+	}
+
+	/// Registers a new upload and returns its token
+	pub async fn create(&self, stash: &Stash, name: &str, size: u64, update_time: u64) -> Result<String> {
+		let mut token = [0; 16];
+		openssl::rand::rand_bytes(&mut token)?;
+		let token = token.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+
+		fs::create_dir_all(staging_dir()).await?;
+		Encoder::create(&staged_path(&token), size).await?.finish().await?;
+
+		let mut db = self.0.acquire().await?;
+		query!(
+			"INSERT INTO upload (token, stash, name, size, update_time) VALUES (?, ?, ?, ?, ?)",
+			token,
+			stash.id(),
+			name,
+			size,
+			update_time
+		).execute(&mut db).await?;
+		Ok(token)
+	}
+
+	pub async fn get(&self, owner: u64, token: &str) -> Result<Option<Staged>> {
+		let mut db = self.0.acquire().await?;
+		let query = query!(
+			"SELECT stash.name AS stash, upload.name, upload.size, upload.update_time
+				FROM upload JOIN stash ON upload.stash=stash.id
+				WHERE upload.token=? AND stash.owner=?",
+			token,
+			owner
+		);
+		Ok(query.fetch_optional(&mut db).await?.map(|res| Staged {
+			stash: res.stash,
+			name: res.name,
+			size: res.size,
+			update_time: res.update_time
+		}))
+	}
+
+	/// Number of bytes already received for an upload, the data is encoded as it arrives
+	pub async fn received(token: &str) -> io::Result<u64> {
+		format::written_len(&staged_path(token)).await
+	}
+
+	/// Forgets an upload and removes its data if it's still there
+	pub async fn remove(&self, token: &str) -> Result<()> {
+		let mut db = self.0.acquire().await?;
+		query!("DELETE FROM upload WHERE token=?", token).execute(&mut db).await?;
+		match fs::remove_file(staged_path(token)).await {
+			Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+			_ => Ok(())
+		}
+	}
+
+	/// Removes uploads that didn't receive any data for the configured time
+	pub async fn expire(&self) -> Result<()> {
+		let max_age = Duration::from_secs(Config::get().upload_expiry);
+		let mut entries = match fs::read_dir(staging_dir()).await {
+			Ok(entries) => entries,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(err) => return Err(err.into())
+		};
+		while let Some(entry) = entries.next().await {
+			let entry = entry?;
+			let modified = entry.metadata().await?.modified()?;
+			let expired = match SystemTime::now().duration_since(modified) {
+				Ok(age) => age > max_age,
+				Err(_) => false
+			};
+			if expired {
+				match entry.file_name().into_string() {
+					Ok(token) => {
+						debug!("Removing an expired upload {token}");
+						self.remove(&token).await?;
+					}
+					Err(name) => warning!("Unexpected file in the staging area: {name:?}")
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+pub fn staged_path(token: &str) -> String {
+	format!("{}/{token}", staging_dir())
+}
+
+fn staging_dir() -> String {
+	format!("{}/staging", Config::get().storage_path)
+}
@@ -1,28 +1,607 @@
-use std::collections::HashMap;
+use std::{
+	collections::{BTreeMap, HashMap},
+	ops::Bound,
+	sync::atomic::{AtomicU64, Ordering},
+	time::{SystemTime, UNIX_EPOCH}
+};
 use anyhow::Result;
+use async_std::fs;
+use sha3::{Sha3_256, Digest};
 use sqlx::{MySqlPool, query};
-use super::file::File;
+use crate::{config::Config, warning};
+use super::{
+	blob,
+	file::{self, File, Record},
+	format::Encoder
+};
+
+static IMPORT_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 pub struct Stash {
-	files: HashMap<String, (u64, u64)>
+	db: MySqlPool,
+	id: u64,
+	/// Set for read-only views of past states of the stash
+	view: bool,
+	/// Sorted by name so listings can be paged without sorting everything
+	files: BTreeMap<String, Record>
 }
 
 impl Stash {
-	pub async fn new(db: &MySqlPool, id: u64) -> Result<Self> {
-		let mut db = db.acquire().await?;
+	pub async fn new(db_pool: &MySqlPool, id: u64) -> Result<Self> {
+		let mut db = db_pool.acquire().await?;
 		let query = query!(
-			"SELECT id, name, update_time FROM file WHERE stash=?",
+			"SELECT id, name, revision, update_time, size, hash FROM file WHERE stash=?",
 			id
 		);
-		let mut files = HashMap::new();
+		let mut files = BTreeMap::new();
+		for res in query.fetch_all(&mut db).await? {
+			files.insert(res.name, Record {
+				id: res.id,
+				revision: res.revision,
+				update_time: res.update_time,
+				size: res.size,
+				hash: res.hash
+			});
+		}
+		// sizes weren't recorded for files stored before hashes
+		for record in files.values_mut().filter(|record| record.hash.is_none() && record.size == 0) {
+			let path = record.path();
+			match file::stored_size(&path).await {
+				Ok(0) => (),
+				Ok(size) => {
+					query!("UPDATE file SET size=? WHERE id=?", size, record.id).execute(&mut db).await?;
+					record.size = size;
+				}
+				Err(err) => warning!("Can't get size of stored file <{path}>: {err}")
+			}
+		}
+		Ok(Stash {
+			db: db_pool.clone(),
+			id,
+			view: false,
+			files
+		})
+	}
+
+	/// Loads contents of a stash as they were when a snapshot was taken.
+	/// Returns `None` if there's no such snapshot.
+	pub async fn from_snapshot(db_pool: &MySqlPool, id: u64, snapshot: &str) -> Result<Option<Self>> {
+		let mut db = db_pool.acquire().await?;
+		let query = query!(
+			"SELECT id FROM snapshot WHERE stash=? AND name=?",
+			id,
+			snapshot
+		);
+		let snapshot = match query.fetch_optional(&mut db).await? {
+			Some(res) => res.id,
+			None => return Ok(None)
+		};
+		let query = query!(
+			"SELECT name, update_time, size, hash FROM snapshot_file WHERE snapshot=?",
+			snapshot
+		);
+		let mut files = BTreeMap::new();
 		for res in query.fetch_all(&mut db).await? {
-			files.insert(res.name, (res.id, res.update_time));
+			files.insert(res.name, Record {
+				id: 0,
+				revision: 0,
+				update_time: res.update_time,
+				size: res.size,
+				hash: Some(res.hash)
+			});
 		}
-		Ok(Stash { files })
+		Ok(Some(Stash {
+			db: db_pool.clone(),
+			id,
+			view: true,
+			files
+		}))
+	}
+
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+
+	pub fn record(&self, name: &str) -> Option<&Record> {
+		self.files.get(name)
+	}
+
+	/// Names of files starting with `prefix` in sorted order
+	pub fn names<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> {
+		self.files.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+			.map(|(name, _)| name.as_str())
+			.take_while(move |name| name.starts_with(prefix))
+	}
+
+	/// Files and implied directories right under `dir`, sorted by name.
+	/// `dir` has no slashes around it, an empty one is the top level.
+	/// Returns `None` if there is nothing under a non-empty `dir`.
+	pub fn children(&self, dir: &str) -> Option<Vec<Entry>> {
+		let mut entries: HashMap<&str, Entry> = HashMap::new();
+		for (name, record) in &self.files {
+			let rest = if dir.is_empty() {
+				name.as_str()
+			} else {
+				match name.strip_prefix(dir).and_then(|rest| rest.strip_prefix('/')) {
+					Some(rest) => rest,
+					None => continue
+				}
+			};
+			let (child, is_dir) = match rest.split_once('/') {
+				Some((child, _)) => (child, true),
+				None => (rest, false)
+			};
+			// a file and a directory can share a name
+			let key = &name[name.len() - rest.len()..][..child.len() + is_dir as usize];
+			let entry = entries.entry(key).or_insert_with(|| Entry {
+				name: child.into(),
+				dir: is_dir,
+				size: 0,
+				update_time: 0
+			});
+			entry.size += record.size;
+			entry.update_time = entry.update_time.max(record.update_time);
+		}
+		if entries.is_empty() && !dir.is_empty() {
+			return None;
+		}
+		let mut res: Vec<Entry> = entries.into_values().collect();
+		res.sort_by(|a, b| a.name.cmp(&b.name).then(a.dir.cmp(&b.dir)));
+		Some(res)
 	}
 
 	pub fn get(&self, name: &str) -> Option<File> {
-		self.files.get(name).map(|(id, upd)| File::new(*id, *upd)).flatten()
+		self.files.get(name).map(File::new).flatten()
+	}
+
+	/// Builds a read-only view of files as they were at the given time
+	/// based on update times of kept revisions
+	pub async fn as_of(&self, time: u64) -> Result<Self> {
+		let mut files: BTreeMap<String, Record> = self.files.iter()
+			.filter(|(_, record)| record.update_time <= time)
+			.map(|(name, record)| (name.clone(), record.clone()))
+			.collect();
+		if !self.view {
+			let mut db = self.db.acquire().await?;
+			let query = query!(
+				"SELECT file.name, revision.file, revision.revision, revision.update_time, revision.size, revision.hash
+					FROM revision JOIN file ON revision.file=file.id
+					WHERE file.stash=? AND revision.update_time<=?",
+				self.id,
+				time
+			);
+			for res in query.fetch_all(&mut db).await? {
+				let newer = match files.get(&res.name) {
+					Some(record) => (res.update_time, res.revision) > (record.update_time, record.revision),
+					None => true
+				};
+				if newer {
+					files.insert(res.name, Record {
+						id: res.file,
+						revision: res.revision,
+						update_time: res.update_time,
+						size: res.size,
+						hash: res.hash
+					});
+				}
+			}
+		}
+		Ok(Stash {
+			db: self.db.clone(),
+			id: self.id,
+			view: true,
+			files
+		})
+	}
+
+	/// Changes that turn this stash into `newer`, sorted by file name
+	pub fn diff<'a>(&'a self, newer: &'a Stash) -> Vec<Change<'a>> {
+		let mut res: Vec<Change> = self.files.iter().filter_map(|(name, record)| match newer.files.get(name) {
+			Some(new_record) if record.same_content(new_record) => None,
+			Some(_) => Some(Change::Modified(name)),
+			None => Some(Change::Removed(name))
+		}).chain(
+			newer.files.keys().filter(|name| !self.files.contains_key(*name)).map(|name| Change::Added(name))
+		).collect();
+		res.sort_by_key(|change| change.name());
+		res
+	}
+
+	/// All kept revisions of a file, the newest first.
+	/// Returns `None` if there's no such file.
+	pub async fn revisions(&self, name: &str) -> Result<Option<Vec<Record>>> {
+		match self.files.get(name) {
+			Some(current) if self.view => Ok(Some(vec![current.clone()])),
+			Some(current) => {
+				let mut db = self.db.acquire().await?;
+				let query = query!(
+					"SELECT revision, update_time, size, hash FROM revision WHERE file=? ORDER BY revision DESC",
+					current.id
+				);
+				let mut res = vec![current.clone()];
+				res.extend(query.fetch_all(&mut db).await?.into_iter().map(|res| Record {
+					id: current.id,
+					revision: res.revision,
+					update_time: res.update_time,
+					size: res.size,
+					hash: res.hash
+				}));
+				Ok(Some(res))
+			}
+			None => Ok(None)
+		}
+	}
+
+	pub async fn get_revision(&self, name: &str, revision: u64) -> Result<Option<File>> {
+		match self.files.get(name) {
+			Some(current) if current.revision == revision => Ok(File::new(current)),
+			Some(_) if self.view => Ok(None),
+			Some(current) => {
+				let mut db = self.db.acquire().await?;
+				let query = query!(
+					"SELECT update_time, size, hash FROM revision WHERE file=? AND revision=?",
+					current.id,
+					revision
+				);
+				Ok(query.fetch_optional(&mut db).await?.map(|res| Record {
+					id: current.id,
+					revision,
+					update_time: res.update_time,
+					size: res.size,
+					hash: res.hash
+				}).as_ref().map(File::new).flatten())
+			}
+			None => Ok(None)
+		}
+	}
+
+	/// Creates or updates a file with data received at `path`.
+	/// The stash itself is not updated, it has to be reloaded.
+	pub async fn put_file(&self, name: &str, update_time: u64, size: u64, hash: &str, path: &str) -> Result<()> {
+		blob::acquire(&self.db, path, hash, size).await?;
+		match self.link(name, update_time, size, hash, true).await {
+			Ok(_) => Ok(()),
+			Err(err) => {
+				blob::release(&self.db, hash).await?;
+				Err(err)
+			}
+		}
+	}
+
+	/// Points a file record to a blob the caller holds a reference to.
+	/// The reference is passed to the record, the old content of the file
+	/// is kept as a revision or released.
+	/// Returns `false` without taking the reference if the file exists and mustn't be replaced.
+	async fn link(&self, name: &str, update_time: u64, size: u64, hash: &str, replace: bool) -> Result<bool> {
+		let keep = Config::get().keep_revisions;
+		let mut tx = self.db.begin().await?;
+		let query = query!(
+			"SELECT id, revision, update_time, size, hash FROM file WHERE stash=? AND name=? FOR UPDATE",
+			self.id,
+			name
+		);
+		let released = match query.fetch_optional(&mut tx).await? {
+			Some(_) if !replace => return Ok(false),
+			Some(old) => {
+				query!(
+					"UPDATE file SET update_time=?, size=?, hash=?, revision=revision+1 WHERE id=?",
+					update_time,
+					size,
+					hash,
+					old.id
+				).execute(&mut tx).await?;
+				if keep > 0 {
+					query!(
+						"INSERT INTO revision (file, revision, update_time, size, hash) VALUES (?, ?, ?, ?, ?)",
+						old.id,
+						old.revision,
+						old.update_time,
+						old.size,
+						old.hash
+					).execute(&mut tx).await?;
+					let query = query!(
+						"SELECT revision, hash FROM revision WHERE file=? ORDER BY revision DESC",
+						old.id
+					);
+					let mut released = vec![];
+					for res in query.fetch_all(&mut tx).await?.into_iter().skip(keep as usize) {
+						query!(
+							"DELETE FROM revision WHERE file=? AND revision=?",
+							old.id,
+							res.revision
+						).execute(&mut tx).await?;
+						released.push((old.id, res.hash));
+					}
+					released
+				} else {
+					vec![(old.id, old.hash)]
+				}
+			}
+			None => {
+				query!(
+					"INSERT INTO file (stash, name, update_time, size, hash) VALUES (?, ?, ?, ?, ?)",
+					self.id,
+					name,
+					update_time,
+					size,
+					hash
+				).execute(&mut tx).await?;
+				vec![]
+			}
+		};
+		tx.commit().await?;
+		for (id, hash) in released {
+			file::release(&self.db, id, hash.as_deref()).await?;
+		}
+		Ok(true)
+	}
+
+	/// Moves a file with its revisions to `dest_name` in `dest`, which may be this stash.
+	/// Only the record changes, the stored data stays where it is.
+	pub async fn move_file(&self, name: &str, dest: &Stash, dest_name: &str) -> Result<Transfer> {
+		let mut tx = self.db.begin().await?;
+		let query = query!(
+			"SELECT id FROM file WHERE stash=? AND name=? FOR UPDATE",
+			self.id,
+			name
+		);
+		let id = match query.fetch_optional(&mut tx).await? {
+			Some(res) => res.id,
+			None => return Ok(Transfer::NoFile)
+		};
+		let query = query!(
+			"SELECT id FROM file WHERE stash=? AND name=? FOR UPDATE",
+			dest.id,
+			dest_name
+		);
+		if query.fetch_optional(&mut tx).await?.is_some() {
+			return Ok(Transfer::Exists);
+		}
+		query!(
+			"UPDATE file SET stash=?, name=? WHERE id=?",
+			dest.id,
+			dest_name,
+			id
+		).execute(&mut tx).await?;
+		tx.commit().await?;
+		Ok(Transfer::Done)
+	}
+
+	/// Adds the current version of a file to `dest` as `dest_name`, sharing the stored data.
+	/// Neither stash is updated, they have to be reloaded.
+	pub async fn copy_file(&self, name: &str, dest: &Stash, dest_name: &str) -> Result<Transfer> {
+		let record = match self.files.get(name) {
+			Some(record) => record,
+			None => return Ok(Transfer::NoFile)
+		};
+		if dest.files.contains_key(dest_name) {
+			return Ok(Transfer::Exists);
+		}
+		let hash = match record.hash {
+			Some(ref hash) => if blob::add_ref(&self.db, hash).await? {
+				hash.clone()
+			} else {
+				warning!("Blob {hash} of file {} is missing", record.id);
+				return Ok(Transfer::NoFile);
+			}
+			None => match self.import(record).await? {
+				Some(hash) => hash,
+				None => return Ok(Transfer::NoFile)
+			}
+		};
+		// the cached list of files could be out of date
+		match dest.link(dest_name, record.update_time, record.size, &hash, false).await {
+			Ok(true) => Ok(Transfer::Done),
+			Ok(false) => {
+				blob::release(&self.db, &hash).await?;
+				Ok(Transfer::Exists)
+			}
+			Err(err) => {
+				blob::release(&self.db, &hash).await?;
+				Err(err)
+			}
+		}
+	}
+
+	/// Stores a copy of a file kept from before hashes were recorded as a blob.
+	/// Returns the hash of the blob with a reference held for the caller.
+	async fn import(&self, record: &Record) -> Result<Option<String>> {
+		let mut reader = match File::new(record) {
+			Some(file) => file.open().await?,
+			None => return Ok(None)
+		};
+		let path = format!(
+			"{}/.copy-{}-{}",
+			Config::get().storage_path,
+			std::process::id(),
+			IMPORT_COUNTER.fetch_add(1, Ordering::Relaxed)
+		);
+		let res = async {
+			let mut encoder = Encoder::create(&path, reader.len()).await?;
+			let mut hasher = Sha3_256::new();
+			let mut size = 0;
+			let mut buf = vec![0; 65536];
+			loop {
+				let len = reader.read(&mut buf).await?;
+				if len == 0 {
+					break;
+				}
+				hasher.update(&buf[..len]);
+				encoder.write(&buf[..len]).await?;
+				size += len as u64;
+			}
+			encoder.finish().await?;
+			Ok::<_, std::io::Error>((format!("{:x}", hasher.finalize()), size))
+		}.await;
+		match res {
+			Ok((hash, size)) => {
+				blob::acquire(&self.db, &path, &hash, size).await?;
+				Ok(Some(hash))
+			}
+			Err(err) => {
+				let _ = fs::remove_file(&path).await;
+				Err(err.into())
+			}
+		}
+	}
+
+	/// Moves data of files stored before hashes were recorded into the blob store
+	/// so they can be referenced like any other file
+	async fn adopt_legacy(&self) -> Result<()> {
+		for record in self.files.values().filter(|record| record.hash.is_none()) {
+			let hash = match self.import(record).await? {
+				Some(hash) => hash,
+				None => continue
+			};
+			let mut db = self.db.acquire().await?;
+			let query = query!(
+				"UPDATE file SET hash=? WHERE id=? AND hash IS NULL",
+				hash,
+				record.id
+			);
+			if query.execute(&mut db).await?.rows_affected() > 0 {
+				let path = record.path();
+				if let Err(err) = fs::remove_file(&path).await {
+					warning!("Can't remove <{path}> after moving it to the blob store: {err}");
+				}
+			} else {
+				// the file was replaced or deleted in the meantime
+				blob::release(&self.db, &hash).await?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Records current contents of the stash under a name.
+	/// Files stored before hashes were recorded are moved to the blob store first,
+	/// so the stash has to be reloaded afterwards.
+	/// Returns `false` if a snapshot with this name already exists.
+	pub async fn create_snapshot(&self, name: &str) -> Result<bool> {
+		let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+		self.adopt_legacy().await?;
+		let mut tx = self.db.begin().await?;
+		let query = query!(
+			"SELECT id FROM snapshot WHERE stash=? AND name=?",
+			self.id,
+			name
+		);
+		if query.fetch_optional(&mut tx).await?.is_some() {
+			return Ok(false);
+		}
+		let snapshot = query!(
+			"INSERT INTO snapshot (stash, name, time) VALUES (?, ?, ?)",
+			self.id,
+			name,
+			time
+		).execute(&mut tx).await?.last_insert_id();
+		query!(
+			"INSERT INTO snapshot_file (snapshot, name, update_time, size, hash)
+				SELECT ?, name, update_time, size, hash FROM file WHERE stash=? AND hash IS NOT NULL",
+			snapshot,
+			self.id
+		).execute(&mut tx).await?;
+		let hashes = query!("SELECT hash FROM snapshot_file WHERE snapshot=?", snapshot)
+			.fetch_all(&mut tx).await?
+			.into_iter()
+			.map(|res| res.hash)
+			.collect::<Vec<_>>();
+		tx.commit().await?;
+
+		let mut db = self.db.acquire().await?;
+		for hash in blob::add_refs(&self.db, &hashes).await? {
+			// the file was replaced while the snapshot was taken
+			warning!("Blob {hash} disappeared while taking snapshot #{snapshot}");
+			query!(
+				"DELETE FROM snapshot_file WHERE snapshot=? AND hash=?",
+				snapshot,
+				hash
+			).execute(&mut db).await?;
+		}
+		Ok(true)
+	}
+
+	/// `(creation time, name)` of every snapshot of the stash
+	pub async fn snapshots(&self) -> Result<Vec<(u64, String)>> {
+		let mut db = self.db.acquire().await?;
+		let query = query!(
+			"SELECT name, time FROM snapshot WHERE stash=? ORDER BY time",
+			self.id
+		);
+		Ok(query.fetch_all(&mut db).await?.into_iter().map(|res| (res.time, res.name)).collect())
+	}
+
+	/// Returns `false` if there was no such snapshot
+	pub async fn delete_snapshot(&self, name: &str) -> Result<bool> {
+		let mut db = self.db.acquire().await?;
+		let query = query!(
+			"SELECT id FROM snapshot WHERE stash=? AND name=?",
+			self.id,
+			name
+		);
+		match query.fetch_optional(&mut db).await? {
+			Some(res) => {
+				let hashes = query!("SELECT hash FROM snapshot_file WHERE snapshot=?", res.id).fetch_all(&mut db).await?;
+				query!("DELETE FROM snapshot WHERE id=?", res.id).execute(&mut db).await?;
+				for res in hashes {
+					blob::release(&self.db, &res.hash).await?;
+				}
+				Ok(true)
+			}
+			None => Ok(false)
+		}
+	}
+
+	/// Removes a file record along with its data and all of its revisions.
+	/// Returns `false` if there was no such file.
+	pub async fn remove_file(&self, name: &str) -> Result<bool> {
+		let mut db = self.db.acquire().await?;
+		let query = query!(
+			"SELECT id, hash FROM file WHERE stash=? AND name=?",
+			self.id,
+			name
+		);
+		match query.fetch_optional(&mut db).await? {
+			Some(res) => {
+				let revisions = query!("SELECT hash FROM revision WHERE file=?", res.id).fetch_all(&mut db).await?;
+				query!("DELETE FROM file WHERE id=?", res.id).execute(&mut db).await?;
+				file::release(&self.db, res.id, res.hash.as_deref()).await?;
+				for revision in revisions {
+					file::release(&self.db, res.id, revision.hash.as_deref()).await?;
+				}
+				Ok(true)
+			}
+			None => Ok(false)
+		}
+	}
+}
+
+/// A file or an implied directory
+pub struct Entry {
+	pub name: String,
+	pub dir: bool,
+	pub size: u64,
+	/// The newest update time of anything inside
+	pub update_time: u64
+}
+
+pub enum Transfer {
+	Done,
+	NoFile,
+	Exists
+}
+
+pub enum Change<'a> {
+	Added(&'a str),
+	Removed(&'a str),
+	Modified(&'a str)
+}
+
+impl<'a> Change<'a> {
+	pub fn name(&self) -> &'a str {
+		use Change::*;
+		match *self {
+			Added(name) | Removed(name) | Modified(name) => name
+		}
 	}
 }
 
@@ -35,14 +614,14 @@ impl<'a> IntoIterator for &'a Stash {
 	}
 }
 
-pub struct Files<'a>(<&'a HashMap<String, (u64, u64)> as IntoIterator>::IntoIter);
+pub struct Files<'a>(<&'a BTreeMap<String, Record> as IntoIterator>::IntoIter);
 
 impl<'a> Iterator for Files<'a> {
     type Item = (&'a str, File);
 
     fn next(&mut self) -> Option<Self::Item> {
 		match self.0.next() {
-			Some((name, (id, upd))) => match File::new(*id, *upd) {
+			Some((name, record)) => match File::new(record) {
 				Some(file) => Some((name, file)),
 				None => self.next()
 			}
@@ -3,7 +3,7 @@ use anyhow::Result;
 use async_std::sync::{Arc, Weak, Mutex};
 use sha3::{Sha3_256, Digest};
 use sqlx::{MySqlPool, query};
-use super::stash::Stash;
+use super::{stash::Stash, file, blob};
 
 #[derive(Clone)]
 pub struct UserPool {
@@ -123,6 +123,225 @@ impl User {
 		}
 	}
 
+	/// Finds a stash by name or a snapshot of it as `<stash>:<snapshot>`.
+	/// An existing stash takes priority if its name contains a colon.
+	pub async fn get_view(&self, name: &str) -> Result<Option<Arc<Stash>>> {
+		if let Some(stash) = self.get_stash(name).await? {
+			return Ok(Some(stash));
+		}
+		match name.rsplit_once(':') {
+			Some((stash, snapshot)) => match self.get_stash(stash).await? {
+				Some(stash) => self.get_snapshot(&stash, snapshot).await,
+				None => Ok(None)
+			}
+			None => Ok(None)
+		}
+	}
+
+	pub async fn get_snapshot(&self, stash: &Stash, snapshot: &str) -> Result<Option<Arc<Stash>>> {
+		match self.db {
+			Some(ref db) => Ok(Stash::from_snapshot(db, stash.id(), snapshot).await?.map(Arc::new)),
+			None => Ok(None)
+		}
+	}
+
+	/// Returns `false` if a stash with this name already exists
+	pub async fn create_stash(&self, name: &str) -> Result<bool> {
+		match self.db {
+			Some(ref db) => {
+				let mut db = db.acquire().await?;
+				let query = query!(
+					"SELECT id FROM stash WHERE owner=? AND name=?",
+					self.id,
+					name
+				);
+				if query.fetch_optional(&mut db).await?.is_some() {
+					return Ok(false);
+				}
+				query!(
+					"INSERT INTO stash (owner, name) VALUES (?, ?)",
+					self.id,
+					name
+				).execute(&mut db).await?;
+				Ok(true)
+			}
+			None => Ok(false)
+		}
+	}
+
+	/// Removes a stash with all of its files.
+	/// Returns `false` if there was no such stash.
+	pub async fn delete_stash(&self, name: &str) -> Result<bool> {
+		match self.db {
+			Some(ref db_pool) => {
+				let mut db = db_pool.acquire().await?;
+				let query = query!(
+					"SELECT id FROM stash WHERE owner=? AND name=?",
+					self.id,
+					name
+				);
+				let id = match query.fetch_optional(&mut db).await? {
+					Some(res) => res.id,
+					None => return Ok(false)
+				};
+				let files = query!("SELECT id, hash FROM file WHERE stash=?", id).fetch_all(&mut db).await?;
+				let revisions = query!(
+					"SELECT revision.file, revision.hash FROM revision JOIN file ON revision.file=file.id WHERE file.stash=?",
+					id
+				).fetch_all(&mut db).await?;
+				let snapshot_files = query!(
+					"SELECT snapshot_file.hash FROM snapshot_file JOIN snapshot ON snapshot_file.snapshot=snapshot.id WHERE snapshot.stash=?",
+					id
+				).fetch_all(&mut db).await?;
+				query!("DELETE FROM stash WHERE id=?", id).execute(&mut db).await?;
+				self.forget_stash(name).await;
+				for res in files {
+					file::release(db_pool, res.id, res.hash.as_deref()).await?;
+				}
+				for res in revisions {
+					file::release(db_pool, res.file, res.hash.as_deref()).await?;
+				}
+				for res in snapshot_files {
+					blob::release(db_pool, &res.hash).await?;
+				}
+				Ok(true)
+			}
+			None => Ok(false)
+		}
+	}
+
+	pub async fn rename_stash(&self, old_name: &str, new_name: &str) -> Result<RenameResult> {
+		match self.db {
+			Some(ref db) => {
+				let mut db = db.acquire().await?;
+				let query = query!(
+					"SELECT name FROM stash WHERE owner=? AND (name=? OR name=?)",
+					self.id,
+					old_name,
+					new_name
+				);
+				let found: Vec<String> = query.fetch_all(&mut db).await?.into_iter().map(|res| res.name).collect();
+				if !found.iter().any(|name| name == old_name) {
+					Ok(RenameResult::NoSource)
+				} else if found.iter().any(|name| name == new_name) {
+					Ok(RenameResult::Exists)
+				} else {
+					query!(
+						"UPDATE stash SET name=? WHERE owner=? AND name=?",
+						new_name,
+						self.id,
+						old_name
+					).execute(&mut db).await?;
+					self.forget_stash(old_name).await;
+					self.forget_stash(new_name).await;
+					Ok(RenameResult::Done)
+				}
+			}
+			None => Ok(RenameResult::NoSource)
+		}
+	}
+
+	/// Checks if storing `size` bytes more in the stash keeps the stash and the user within quotas.
+	/// `freed` is the size of data leaving another stash of the user at the same time.
+	/// Replaced content isn't counted as freed, revisions and snapshots can still hold it.
+	pub async fn fits_quota(&self, stash: &Stash, size: u64, freed: u64) -> Result<bool> {
+		let stash_fits = match self.usage().await?.iter().find(|usage| usage.id == stash.id()) {
+			Some(Usage { used, quota: Some(quota), .. }) => used + size <= *quota,
+			_ => true
+		};
+		let user_fits = match self.quota().await? {
+			Some(quota) => self.used().await?.saturating_sub(freed) + size <= quota,
+			None => true
+		};
+		Ok(stash_fits && user_fits)
+	}
+
+	/// `None` means there is no limit
+	pub async fn quota(&self) -> Result<Option<u64>> {
+		match self.db {
+			Some(ref db) => {
+				let mut db = db.acquire().await?;
+				let query = query!("SELECT quota FROM user WHERE id=?", self.id);
+				Ok(query.fetch_one(&mut db).await?.quota)
+			}
+			None => Ok(None)
+		}
+	}
+
+	/// Space used in every stash by current versions of files, kept revisions and snapshots.
+	/// Content shared by several of them is counted once per stash.
+	pub async fn usage(&self) -> Result<Vec<Usage>> {
+		match self.db {
+			Some(ref db) => {
+				let mut db = db.acquire().await?;
+				// data stored before the blob store is told apart by the ID of its file
+				let query = query!(
+					"SELECT stash.id, stash.name, stash.quota,
+						CAST((SELECT COUNT(*) FROM file WHERE file.stash=stash.id) AS UNSIGNED) AS `files!: u64`,
+						CAST(COALESCE(SUM(content.size), 0) AS UNSIGNED) AS `used!: u64`
+						FROM stash LEFT JOIN (
+							SELECT stash, content, MAX(size) AS size FROM (
+								SELECT file.stash, COALESCE(file.hash, CONCAT('#', file.id)) AS content, file.size
+									FROM file JOIN stash ON file.stash=stash.id WHERE stash.owner=?
+								UNION SELECT file.stash, COALESCE(revision.hash, CONCAT('#', revision.file)), revision.size
+									FROM revision JOIN file ON revision.file=file.id JOIN stash ON file.stash=stash.id
+									WHERE stash.owner=?
+								UNION SELECT snapshot.stash, snapshot_file.hash, snapshot_file.size
+									FROM snapshot_file JOIN snapshot ON snapshot_file.snapshot=snapshot.id
+									JOIN stash ON snapshot.stash=stash.id WHERE stash.owner=?
+							) AS refs GROUP BY stash, content
+						) AS content ON content.stash=stash.id
+						WHERE stash.owner=? GROUP BY stash.id ORDER BY stash.name",
+					self.id,
+					self.id,
+					self.id,
+					self.id
+				);
+				Ok(query.fetch_all(&mut db).await?.into_iter().map(|res| Usage {
+					id: res.id,
+					name: res.name,
+					quota: res.quota,
+					files: res.files,
+					used: res.used
+				}).collect())
+			}
+			None => Ok(vec![])
+		}
+	}
+
+	/// Space used by all stashes of the user, content shared between them is counted once
+	pub async fn used(&self) -> Result<u64> {
+		match self.db {
+			Some(ref db) => {
+				let mut db = db.acquire().await?;
+				let query = query!(
+					"SELECT CAST(COALESCE(SUM(size), 0) AS UNSIGNED) AS `used!: u64` FROM (
+						SELECT content, MAX(size) AS size FROM (
+							SELECT COALESCE(file.hash, CONCAT('#', file.id)) AS content, file.size
+								FROM file JOIN stash ON file.stash=stash.id WHERE stash.owner=?
+							UNION SELECT COALESCE(revision.hash, CONCAT('#', revision.file)), revision.size
+								FROM revision JOIN file ON revision.file=file.id JOIN stash ON file.stash=stash.id
+								WHERE stash.owner=?
+							UNION SELECT snapshot_file.hash, snapshot_file.size
+								FROM snapshot_file JOIN snapshot ON snapshot_file.snapshot=snapshot.id
+								JOIN stash ON snapshot.stash=stash.id WHERE stash.owner=?
+						) AS refs GROUP BY content
+					) AS content",
+					self.id,
+					self.id,
+					self.id
+				);
+				Ok(query.fetch_one(&mut db).await?.used)
+			}
+			None => Ok(0)
+		}
+	}
+
+	/// Drops a cached stash so it will be reloaded on the next access
+	pub async fn forget_stash(&self, name: &str) {
+		self.stashes.lock().await.remove(name);
+	}
+
 	async fn load_stash(&self, stashes: &mut HashMap<String, Weak<Stash>>, name: &str) -> Result<Option<Arc<Stash>>> {
 		match self.db {
 			Some(ref db_pool) => {
@@ -162,3 +381,18 @@ impl User {
 		self.id
 	}
 }
+
+pub struct Usage {
+	pub id: u64,
+	pub name: String,
+	/// Limit for the stash in bytes
+	pub quota: Option<u64>,
+	pub files: u64,
+	pub used: u64
+}
+
+pub enum RenameResult {
+	Done,
+	NoSource,
+	Exists
+}
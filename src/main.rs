@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 use anyhow::Result;
 use config::Config;
 use futures::join;
@@ -20,8 +20,20 @@ mod frontend;
 mod info;
 
 async fn run(args: args::Args) -> Result<()> {
-    let cfg = Config::load(&args.config.unwrap_or("server.cfg".to_string()))?;
+    let cfg = Config::load(args.config.as_deref().unwrap_or("server.cfg"))?;
     let log_handler = log::start(&cfg)?;
+    if let Some(ref path) = cfg.storage_key_file {
+        info::keys::load(path)?;
+    }
+
+    if args.rekey {
+        info!("Rekeying stored data");
+        let res = info::rekey::run().await;
+        log::stop();
+        log_handler.join().unwrap();
+        return res;
+    }
+
     info!("Starting server at {}", cfg.host);
 
     let mut ssl = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
@@ -46,7 +58,18 @@ async fn run(args: args::Args) -> Result<()> {
     let info = Arc::new(ServerInfo {
         ssl: ssl.build(),
         users: info::user::UserPool::new(&db),
-        audit: info::audit::Audit::new(&db)
+        audit: info::audit::Audit::new(&db),
+        staging: info::staging::Staging::new(&db)
+    });
+
+    let expiry_info = info.clone();
+    let expiry = async_std::task::spawn(async move {
+        loop {
+            async_std::task::sleep(Duration::from_secs(60)).await;
+            if let Err(err) = expiry_info.staging.expire().await {
+                error!("Failed to remove expired uploads: {err}");
+            }
+        }
     });
 
     let tasks: Arc<Mutex<(usize, HashMap<usize, JoinHandle<()>>)>> = Arc::new(Mutex::new((0, HashMap::new())));
@@ -78,6 +101,7 @@ async fn run(args: args::Args) -> Result<()> {
     }
 
     info!("Cancelling all tasks");
+    expiry.cancel().await;
     for (_, join) in tasks.lock().await.1.drain() {
         join.cancel().await;
     }
@@ -91,7 +115,8 @@ async fn run(args: args::Args) -> Result<()> {
 pub struct ServerInfo {
     pub ssl: SslAcceptor,
     pub users: info::user::UserPool,
-    pub audit: info::audit::Audit
+    pub audit: info::audit::Audit,
+    pub staging: info::staging::Staging
 }
 
 #[async_std::main]